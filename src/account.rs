@@ -1,48 +1,47 @@
-use crate::transaction::TransactionID;
+use crate::rejection::Rejection;
 use rust_decimal::Decimal;
-use std::collections::HashMap;
 
-#[derive(Debug, PartialEq, Default)]
+/// The balances of a single client.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Account {
+    /// Funds available for withdrawal.
     pub available: Decimal,
-    pub transactions: HashMap<TransactionID, Decimal>,
-    pub held_transactions: HashMap<TransactionID, Decimal>,
+    /// Funds held by transactions currently under dispute.
+    pub held: Decimal,
+    /// Set once a chargeback has occurred.
     pub locked: bool,
+    /// The number of deposits and withdrawals applied, which measures a
+    /// [`DisputeWindow`](crate::DisputeWindow).
+    pub transactions: u64,
 }
 
 impl Account {
     pub fn deposit(&mut self, amount: Decimal) {
         self.available += amount;
+        self.transactions += 1;
     }
-    pub fn withdraw(&mut self, amount: Decimal) {
-        if self.available >= amount {
-            self.available -= amount;
+
+    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), Rejection> {
+        if self.available < amount {
+            return Err(Rejection::InsufficientFunds);
         }
+        self.available -= amount;
+        self.transactions += 1;
+        Ok(())
     }
-    pub fn dispute(&mut self, transaction: TransactionID) {
-        if let Some(amount) = self.transactions.get(&transaction)
-            && !self.held_transactions.contains_key(&transaction)
-        {
-            self.available -= *amount;
-            self.held_transactions.insert(transaction, *amount);
-        }
+
+    pub fn hold(&mut self, amount: Decimal) {
+        self.available -= amount;
+        self.held += amount;
     }
-    pub fn resolve(&mut self, transaction: TransactionID) {
-        if let Some(amount) = self.transactions.get(&transaction)
-            && self.held_transactions.contains_key(&transaction)
-        {
-            self.available += *amount;
-            self.held_transactions.remove(&transaction);
-        }
+
+    pub fn release(&mut self, amount: Decimal) {
+        self.held -= amount;
+        self.available += amount;
     }
 
-    pub fn chargeback(&mut self, transaction: TransactionID) {
-        if let Some(amount) = self.transactions.get(&transaction)
-            && self.held_transactions.contains_key(&transaction)
-        {
-            self.available += *amount;
-            self.held_transactions.remove(&transaction);
-            self.locked = true;
-        }
+    pub fn chargeback(&mut self, amount: Decimal) {
+        self.held -= amount;
+        self.locked = true;
     }
 }
@@ -0,0 +1,157 @@
+use crate::account::Account;
+use crate::output::OutputOrder;
+use crate::rejection::Rejection;
+use crate::state::StoredTransaction;
+use crate::transaction::{Timestamp, Transaction, TransactionType};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// Policies the [`Engine`](crate::Engine) applies transactions under.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Config {
+    pub locked_account_policy: LockedAccountPolicy,
+    pub redispute_policy: RedisputePolicy,
+    pub precision: Precision,
+    pub output_order: OutputOrder,
+    pub dispute_window: DisputeWindow,
+    pub timestamp_order: TimestampOrder,
+}
+
+impl Config {
+    /// Rejects a transaction whose amount is missing, unexpected or more precise than allowed,
+    /// as the engine would whatever state it is in.
+    pub(crate) fn check_amount(&self, transaction: &Transaction) -> Result<(), Rejection> {
+        match (transaction.r#type, transaction.amount) {
+            (TransactionType::Deposit | TransactionType::Withdrawal, Some(amount)) => {
+                self.precision.normalise(amount.get()).map(drop)
+            }
+            (TransactionType::Deposit | TransactionType::Withdrawal, None) | (_, Some(_)) => {
+                Err(Rejection::MalformedAmount)
+            }
+            (_, None) => Ok(()),
+        }
+    }
+}
+
+/// What can still be applied to an account once a chargeback has locked it.
+///
+/// Deposits, withdrawals and new disputes are always rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockedAccountPolicy {
+    /// Transactions already under dispute can still be resolved or charged back.
+    #[default]
+    SettleDisputes,
+    /// Nothing further can be applied.
+    Frozen,
+}
+
+/// Whether a transaction can be disputed again once its dispute has been settled.
+///
+/// A charged back transaction can never be disputed again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedisputePolicy {
+    /// Only the first dispute on a transaction is valid.
+    #[default]
+    Never,
+    /// A resolved transaction can be disputed again.
+    AfterResolve,
+}
+
+/// How long after a deposit or withdrawal it can still be disputed.
+///
+/// A transaction outside either bound can no longer be disputed, and is evicted from the store
+/// so that history stops growing without limit. Only its id is kept, so it is still rejected as
+/// a duplicate. A transaction under dispute is never evicted, so its dispute can always be
+/// settled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DisputeWindow {
+    /// How many later deposits and withdrawals to the same account it takes for a transaction
+    /// to expire.
+    pub transactions: Option<u64>,
+    /// How many milliseconds after a transaction's timestamp it can be disputed. Transactions
+    /// without a timestamp are not bound by this.
+    pub millis: Option<u64>,
+}
+
+impl DisputeWindow {
+    /// Whether any bound is set.
+    pub fn is_bounded(&self) -> bool {
+        self.transactions.is_some() || self.millis.is_some()
+    }
+
+    /// Whether `transaction` is outside the window, given the account it belongs to and the
+    /// current time, if known.
+    pub fn is_expired(
+        &self,
+        transaction: &StoredTransaction,
+        account: &Account,
+        now: Option<Timestamp>,
+    ) -> bool {
+        let too_many = self.transactions.is_some_and(|limit| {
+            account.transactions.saturating_sub(transaction.sequence) >= limit
+        });
+        let too_old = match (self.millis, transaction.timestamp, now) {
+            (Some(limit), Some(timestamp), Some(now)) => {
+                now.millis().saturating_sub(timestamp.millis()) > limit
+            }
+            _ => false,
+        };
+        too_many || too_old
+    }
+}
+
+/// Whether transactions must arrive in timestamp order.
+///
+/// Transactions without a timestamp are never checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampOrder {
+    /// Transactions are applied in the order they arrive, whatever their timestamps.
+    #[default]
+    Unchecked,
+    /// A transaction timestamped before one already applied is rejected.
+    Monotonic,
+}
+
+/// The number of decimal places amounts are accepted and reported with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+    pub decimal_places: u32,
+    pub excess_precision_policy: ExcessPrecisionPolicy,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            decimal_places: 4,
+            excess_precision_policy: ExcessPrecisionPolicy::default(),
+        }
+    }
+}
+
+impl Precision {
+    /// Brings an input amount within `decimal_places`, or rejects it.
+    ///
+    /// An amount that would round to zero is rejected regardless of policy.
+    pub fn normalise(&self, amount: Decimal) -> Result<Decimal, Rejection> {
+        if amount.scale() <= self.decimal_places {
+            return Ok(amount);
+        }
+        match self.excess_precision_policy {
+            ExcessPrecisionPolicy::Reject => Err(Rejection::ExcessPrecision),
+            ExcessPrecisionPolicy::Round(strategy) => {
+                let rounded = amount.round_dp_with_strategy(self.decimal_places, strategy);
+                if rounded.is_zero() {
+                    return Err(Rejection::ExcessPrecision);
+                }
+                Ok(rounded)
+            }
+        }
+    }
+}
+
+/// What to do with an input amount that has more decimal places than allowed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExcessPrecisionPolicy {
+    #[default]
+    Reject,
+    Round(RoundingStrategy),
+}
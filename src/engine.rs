@@ -1,61 +1,255 @@
 use crate::account::Account;
+use crate::config::{Config, LockedAccountPolicy, TimestampOrder};
 use crate::output::AccountOutput;
-use crate::transaction::{ClientID, Transaction, TransactionID, TransactionType};
+use crate::rejection::Rejection;
+use crate::snapshot::Snapshot;
+use crate::state::{DisputeEvent, StoredTransaction, TransactionState};
+use crate::store::{MemoryStore, Store};
+use crate::transaction::{ClientID, Timestamp, Transaction, TransactionID, TransactionType};
 use rust_decimal::Decimal;
 use std::collections::HashMap;
+use std::convert::Infallible;
+
+/// Applies transactions to a set of client accounts, kept in a [`Store`].
+pub struct Engine<S = MemoryStore> {
+    /// Accounts, and every deposit and withdrawal seen whether or not it was applied.
+    store: S,
+    config: Config,
+    /// How many more transactions to handle before evicting expired ones.
+    until_eviction: usize,
+}
+
+/// The fewest transactions handled between evictions. Otherwise each eviction waits for as many
+/// transactions as it left stored, so the scans cost a constant amount per transaction.
+const MIN_EVICTION_INTERVAL: usize = 4096;
+
+/// Why a transaction had no effect: either the engine rejected it, or the store failed.
+enum Failure<E> {
+    Rejected(Rejection),
+    Store(E),
+}
+
+impl<E> From<Rejection> for Failure<E> {
+    fn from(rejection: Rejection) -> Self {
+        Failure::Rejected(rejection)
+    }
+}
 
-#[derive(Default)]
-pub struct Engine {
-    pub accounts: HashMap<ClientID, Account>,
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
 }
 
 impl Engine {
-    pub fn handle_transaction(&mut self, transaction: Transaction) {
+    /// Creates an empty engine that applies transactions under `config`.
+    pub fn new(config: Config) -> Self {
+        Self::with_store(config, MemoryStore::default())
+    }
+
+    /// Applies a single transaction, creating the client's account on its first deposit.
+    ///
+    /// A transaction that is malformed or cannot be applied leaves every balance unchanged and
+    /// returns the reason it was rejected. A well-formed deposit or withdrawal with a new id is
+    /// still recorded as declined when rejected, reserving the id so that a replay of it is
+    /// rejected as a duplicate.
+    pub fn handle_transaction(&mut self, transaction: Transaction) -> Result<(), Rejection> {
+        infallible(self.try_handle_transaction(transaction))
+    }
+
+    /// Looks up the account for a client, if one has been created.
+    pub fn account(&self, client_id: ClientID) -> Option<&Account> {
+        self.store.get_account(client_id)
+    }
+
+    /// Looks up a deposit or withdrawal, including ones that were rejected.
+    pub fn transaction(&self, transaction_id: TransactionID) -> Option<&StoredTransaction> {
+        self.store.get_transaction(transaction_id)
+    }
+
+    /// Summarises every account for output, in the configured order and with balances at the
+    /// configured precision.
+    pub fn output(&self) -> impl Iterator<Item = AccountOutput> {
+        infallible(self.try_output())
+    }
+
+    /// Evicts every stored transaction outside the configured [`DisputeWindow`](crate::DisputeWindow), other than
+    /// those under dispute.
+    pub fn evict_expired(&mut self) {
+        infallible(self.try_evict_expired())
+    }
+
+    /// Captures every account and stored transaction, along with the latest timestamp applied,
+    /// so a later run can carry on from here.
+    pub fn snapshot(&self) -> Snapshot {
+        infallible(self.try_snapshot())
+    }
+
+    /// Creates an engine holding the state captured in `snapshot`, which applies further
+    /// transactions under `config`.
+    pub fn restore(config: Config, snapshot: Snapshot) -> anyhow::Result<Self> {
+        let mut engine = Self::new(config);
+        engine.load(snapshot)?;
+        Ok(engine)
+    }
+}
+
+fn infallible<T>(result: Result<T, Infallible>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(never) => match never {},
+    }
+}
+
+impl<S: Store> Engine<S> {
+    /// Creates an engine that applies transactions under `config`, on top of whatever `store`
+    /// already holds.
+    pub fn with_store(config: Config, store: S) -> Self {
+        Self {
+            store,
+            config,
+            until_eviction: MIN_EVICTION_INTERVAL,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Applies a single transaction, as [`Engine::handle_transaction`], for stores that can
+    /// fail.
+    ///
+    /// If the store fails the transaction may have been partly written, so the store should be
+    /// discarded without a [`flush`](Store::flush).
+    pub fn try_handle_transaction(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<Result<(), Rejection>, S::Error> {
+        let timestamp = transaction.timestamp;
+        let result = match self.apply(transaction) {
+            Ok(()) => {
+                self.advance_clock(timestamp)?;
+                Ok(())
+            }
+            Err(Failure::Rejected(rejection)) => Err(rejection),
+            Err(Failure::Store(error)) => return Err(error),
+        };
+
+        if self.config.dispute_window.is_bounded() {
+            self.until_eviction -= 1;
+            if self.until_eviction == 0 {
+                self.try_evict_expired()?;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Moves the latest timestamp, which [`DisputeWindow::millis`](crate::DisputeWindow::millis)
+    /// is measured up to, forward to `timestamp`.
+    fn advance_clock(&mut self, timestamp: Option<Timestamp>) -> Result<(), S::Error> {
+        if let Some(timestamp) = timestamp
+            && self.store.latest_timestamp()? < Some(timestamp)
+        {
+            self.store.set_latest_timestamp(timestamp)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, transaction: Transaction) -> Result<(), Failure<S::Error>> {
+        let timestamp = transaction.timestamp;
         match transaction {
             Transaction {
                 r#type: TransactionType::Deposit,
                 client,
                 tx,
                 amount: Some(amount),
-            } => self.handle_deposit(client, tx, amount),
+                ..
+            } => {
+                let amount = self.config.precision.normalise(amount.get())?;
+                self.handle_deposit(client, tx, amount, timestamp)
+            }
             Transaction {
                 r#type: TransactionType::Withdrawal,
                 client,
                 tx,
                 amount: Some(amount),
-            } => self.handle_withdrawal(client, tx, amount),
+                ..
+            } => {
+                let amount = self.config.precision.normalise(amount.get())?;
+                self.handle_withdrawal(client, tx, amount, timestamp)
+            }
             Transaction {
                 r#type: TransactionType::Dispute,
                 client,
                 tx,
                 amount,
-            } if amount.is_none() => self.handle_dispute(client, tx),
+                ..
+            } if amount.is_none() => {
+                self.handle_dispute_event(client, tx, DisputeEvent::Dispute, timestamp)
+            }
             Transaction {
                 r#type: TransactionType::Resolve,
                 client,
                 tx,
                 amount,
-            } if amount.is_none() => self.handle_resolve(client, tx),
+                ..
+            } if amount.is_none() => {
+                self.handle_dispute_event(client, tx, DisputeEvent::Resolve, timestamp)
+            }
             Transaction {
                 r#type: TransactionType::Chargeback,
                 client,
                 tx,
                 amount,
-            } if amount.is_none() => self.handle_chargeback(client, tx),
-            // currently ignore if the transaction is malformed
-            _ => (),
+                ..
+            } if amount.is_none() => {
+                self.handle_dispute_event(client, tx, DisputeEvent::Chargeback, timestamp)
+            }
+            _ => Err(Rejection::MalformedAmount.into()),
         }
     }
 
+    /// Evicts every stored transaction outside the configured [`DisputeWindow`](crate::DisputeWindow), as
+    /// [`Engine::evict_expired`], for stores that can fail.
+    ///
+    /// This already happens every so often as transactions are handled.
+    pub fn try_evict_expired(&mut self) -> Result<(), S::Error> {
+        let window = self.config.dispute_window;
+        let remaining = if window.is_bounded() {
+            let accounts: HashMap<_, _> = self.store.accounts()?.into_iter().collect();
+            let now = self.store.latest_timestamp()?;
+            self.store.evict(&mut |_, transaction| {
+                let account = accounts.get(&transaction.client).copied();
+                transaction.state != TransactionState::Disputed
+                    && window.is_expired(transaction, &account.unwrap_or_default(), now)
+            })?
+        } else {
+            0
+        };
+        self.until_eviction = remaining.max(MIN_EVICTION_INTERVAL);
+        Ok(())
+    }
+
     fn handle_deposit(
         &mut self,
         client_id: ClientID,
         transaction_id: TransactionID,
         amount: Decimal,
-    ) {
-        let account = self.accounts.entry(client_id).or_default();
-        account.deposit(amount);
-        account.transactions.insert(transaction_id, amount);
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Failure<S::Error>> {
+        self.check_unique(transaction_id)?;
+        let result = self
+            .check_order(timestamp)
+            .and_then(|()| self.deposit(client_id, amount));
+        self.record(client_id, transaction_id, amount, timestamp, result)
     }
 
     fn handle_withdrawal(
@@ -63,75 +257,296 @@ impl Engine {
         client_id: ClientID,
         transaction_id: TransactionID,
         amount: Decimal,
-    ) {
-        if let Some(account) = self.accounts.get_mut(&client_id) {
-            account.withdraw(amount);
-            account.transactions.insert(transaction_id, -amount);
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Failure<S::Error>> {
+        self.check_unique(transaction_id)?;
+        let result = self
+            .check_order(timestamp)
+            .and_then(|()| self.withdraw(client_id, amount));
+        self.record(client_id, transaction_id, -amount, timestamp, result)
+    }
+
+    /// Returns the account's transaction count once the deposit is applied.
+    fn deposit(&mut self, client_id: ClientID, amount: Decimal) -> Result<u64, Failure<S::Error>> {
+        let mut account = self
+            .store
+            .account(client_id)
+            .map_err(Failure::Store)?
+            .unwrap_or_default();
+        if account.locked {
+            return Err(Rejection::AccountLocked.into());
         }
+        account.deposit(amount);
+        self.store
+            .insert_account(client_id, account)
+            .map_err(Failure::Store)?;
+        Ok(account.transactions)
     }
 
-    fn handle_dispute(&mut self, client_id: ClientID, transaction_id: TransactionID) {
-        if let Some(account) = self.accounts.get_mut(&client_id) {
-            account.dispute(transaction_id);
+    /// Returns the account's transaction count once the withdrawal is applied.
+    fn withdraw(&mut self, client_id: ClientID, amount: Decimal) -> Result<u64, Failure<S::Error>> {
+        let mut account = self
+            .store
+            .account(client_id)
+            .map_err(Failure::Store)?
+            .ok_or(Rejection::UnknownClient)?;
+        if account.locked {
+            return Err(Rejection::AccountLocked.into());
         }
+        account.withdraw(amount)?;
+        self.store
+            .insert_account(client_id, account)
+            .map_err(Failure::Store)?;
+        Ok(account.transactions)
     }
 
-    fn handle_resolve(&mut self, client_id: ClientID, transaction_id: TransactionID) {
-        if let Some(account) = self.accounts.get_mut(&client_id) {
-            account.resolve(transaction_id);
+    /// A transaction id is reserved on first sight so that a replayed transaction is never
+    /// applied twice, even if the original was rejected or has since been evicted.
+    fn check_unique(&self, transaction_id: TransactionID) -> Result<(), Failure<S::Error>> {
+        if self
+            .store
+            .transaction(transaction_id)
+            .map_err(Failure::Store)?
+            .is_some()
+            || self
+                .store
+                .is_evicted(transaction_id)
+                .map_err(Failure::Store)?
+        {
+            return Err(Rejection::DuplicateTransaction.into());
         }
+        Ok(())
     }
 
-    fn handle_chargeback(&mut self, client_id: ClientID, transaction_id: TransactionID) {
-        if let Some(account) = self.accounts.get_mut(&client_id) {
-            account.chargeback(transaction_id);
+    /// Rejects a transaction timestamped before one already applied, when timestamps must be in
+    /// order.
+    fn check_order(&self, timestamp: Option<Timestamp>) -> Result<(), Failure<S::Error>> {
+        if self.config.timestamp_order == TimestampOrder::Monotonic
+            && let Some(timestamp) = timestamp
+            && self.store.latest_timestamp().map_err(Failure::Store)? > Some(timestamp)
+        {
+            return Err(Rejection::OutOfOrder.into());
         }
+        Ok(())
     }
 
-    pub fn output(&self) -> impl Iterator<Item = AccountOutput> {
-        self.accounts.iter().map(AccountOutput::from)
+    fn record(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        amount: Decimal,
+        timestamp: Option<Timestamp>,
+        result: Result<u64, Failure<S::Error>>,
+    ) -> Result<(), Failure<S::Error>> {
+        let (state, sequence, result) = match result {
+            Ok(sequence) => (TransactionState::Processed, sequence, Ok(())),
+            Err(Failure::Rejected(rejection)) => (TransactionState::Declined, 0, Err(rejection)),
+            Err(Failure::Store(error)) => return Err(Failure::Store(error)),
+        };
+        self.store
+            .insert_transaction(
+                transaction_id,
+                StoredTransaction {
+                    client: client_id,
+                    amount,
+                    state,
+                    sequence,
+                    timestamp,
+                },
+            )
+            .map_err(Failure::Store)?;
+        Ok(result?)
+    }
+
+    fn handle_dispute_event(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        event: DisputeEvent,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Failure<S::Error>> {
+        self.check_order(timestamp)?;
+        let Some(mut transaction) = self
+            .store
+            .transaction(transaction_id)
+            .map_err(Failure::Store)?
+        else {
+            if self
+                .store
+                .is_evicted(transaction_id)
+                .map_err(Failure::Store)?
+            {
+                return Err(Rejection::DisputeExpired.into());
+            }
+            return Err(Rejection::UnknownTransaction.into());
+        };
+        if transaction.client != client_id {
+            return Err(Rejection::ClientMismatch.into());
+        }
+        let mut account = self
+            .store
+            .account(client_id)
+            .map_err(Failure::Store)?
+            .ok_or(Rejection::UnknownClient)?;
+        let frozen = self.config.locked_account_policy == LockedAccountPolicy::Frozen;
+        if account.locked && (event == DisputeEvent::Dispute || frozen) {
+            return Err(Rejection::AccountLocked.into());
+        }
+        if event == DisputeEvent::Dispute
+            && self.config.dispute_window.is_expired(
+                &transaction,
+                &account,
+                self.store
+                    .latest_timestamp()
+                    .map_err(Failure::Store)?
+                    .max(timestamp),
+            )
+        {
+            return Err(Rejection::DisputeExpired.into());
+        }
+
+        transaction.state = transaction
+            .state
+            .transition(event, self.config.redispute_policy)?;
+        match event {
+            DisputeEvent::Dispute => account.hold(transaction.amount),
+            DisputeEvent::Resolve => account.release(transaction.amount),
+            DisputeEvent::Chargeback => account.chargeback(transaction.amount),
+        }
+        self.store
+            .insert_transaction(transaction_id, transaction)
+            .map_err(Failure::Store)?;
+        self.store
+            .insert_account(client_id, account)
+            .map_err(Failure::Store)
+    }
+
+    /// Summarises every account for output, as [`Engine::output`], for stores that can fail.
+    pub fn try_output(&self) -> Result<impl Iterator<Item = AccountOutput> + use<S>, S::Error> {
+        let decimal_places = self.config.precision.decimal_places;
+        let mut rows: Vec<_> = self
+            .store
+            .accounts()?
+            .into_iter()
+            .map(|(client, account)| {
+                AccountOutput::from((&client, &account)).rescaled(decimal_places)
+            })
+            .collect();
+        self.config.output_order.sort(&mut rows);
+        Ok(rows.into_iter())
+    }
+
+    /// Summarises one client's account for output, with balances at the configured precision.
+    pub fn try_account_output(&self, client: ClientID) -> Result<Option<AccountOutput>, S::Error> {
+        let decimal_places = self.config.precision.decimal_places;
+        Ok(self
+            .store
+            .account(client)?
+            .map(|account| AccountOutput::from((&client, &account)).rescaled(decimal_places)))
+    }
+
+    /// Captures every account and stored transaction, as [`Engine::snapshot`], for stores that
+    /// can fail.
+    pub fn try_snapshot(&self) -> Result<Snapshot, S::Error> {
+        Ok(Snapshot {
+            accounts: self.store.accounts()?.into_iter().map(Into::into).collect(),
+            transactions: self
+                .store
+                .transactions()?
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            evicted: self.store.evicted()?,
+            latest_timestamp: self.store.latest_timestamp()?,
+            log_sequence: 0,
+        })
+    }
+
+    /// Adds the state captured in `snapshot` to the store, which must not already hold any of
+    /// its accounts or transactions.
+    pub fn load(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        for account in snapshot.accounts {
+            anyhow::ensure!(
+                self.store.account(account.client)?.is_none(),
+                "snapshot has client {} more than once",
+                account.client
+            );
+            self.store.insert_account(
+                account.client,
+                Account {
+                    available: account.available,
+                    held: account.held,
+                    locked: account.locked,
+                    transactions: account.transactions,
+                },
+            )?;
+        }
+        for transaction in snapshot.transactions {
+            anyhow::ensure!(
+                self.store.transaction(transaction.tx)?.is_none(),
+                "snapshot has transaction {} more than once",
+                transaction.tx
+            );
+            self.store.insert_transaction(
+                transaction.tx,
+                StoredTransaction {
+                    client: transaction.client,
+                    amount: transaction.amount,
+                    state: transaction.state,
+                    sequence: transaction.sequence,
+                    timestamp: transaction.timestamp,
+                },
+            )?;
+        }
+        for (first, last) in snapshot.evicted {
+            self.store.insert_evicted(first, last)?;
+        }
+        self.advance_clock(snapshot.latest_timestamp)?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 impl Engine {
+    fn account_count(&self) -> usize {
+        self.store.len()
+    }
+
     fn available_and_held_for_client(&self, client_id: ClientID) -> (f64, f64) {
         use rust_decimal::prelude::ToPrimitive;
 
-        let account = self.accounts.get(&client_id).unwrap();
+        let account = self.account(client_id).unwrap();
         (
             account.available.to_f64().unwrap(),
-            account
-                .held_transactions
-                .values()
-                .sum::<Decimal>()
-                .to_f64()
-                .unwrap(),
+            account.held.to_f64().unwrap(),
         )
     }
 
     fn is_account_locked_for_client(&self, client_id: ClientID) -> bool {
-        self.accounts.get(&client_id).unwrap().locked
+        self.account(client_id).unwrap().locked
     }
 }
 
 #[cfg(test)]
 mod test_deposit {
     use crate::engine::Engine;
+    use crate::rejection::Rejection;
     use crate::transaction::Transaction;
 
     #[test]
     fn no_deposits_creates_no_accounts() {
         let engine = Engine::default();
-        assert!(engine.accounts.is_empty());
+        assert!(engine.account_count() == 0);
     }
 
     #[test]
     fn single_deposit_creates_single_account() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
     }
 
@@ -139,9 +554,13 @@ mod test_deposit {
     fn two_deposits_to_the_same_account() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        engine.handle_transaction(Transaction::deposit(1, 2, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::deposit(1, 2, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((2.0, 0.0), engine.available_and_held_for_client(1));
     }
 
@@ -149,37 +568,63 @@ mod test_deposit {
     fn two_deposits_to_separate_accounts() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        engine.handle_transaction(Transaction::deposit(2, 2, 1.0));
-        assert_eq!(2, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::deposit(2, 2, 1.0))
+            .unwrap();
+        assert_eq!(2, engine.account_count());
         for client in [1, 2] {
             assert_eq!((1.0, 0.0), engine.available_and_held_for_client(client));
         }
     }
+
+    #[test]
+    fn deposit_without_amount_is_malformed() {
+        let mut engine = Engine::default();
+
+        assert_eq!(
+            Err(Rejection::MalformedAmount),
+            engine.handle_transaction(Transaction {
+                amount: None,
+                ..Transaction::deposit(1, 1, 1.0)
+            })
+        );
+        assert!(engine.account_count() == 0);
+    }
 }
 #[cfg(test)]
 mod test_withdrawal {
     use crate::engine::Engine;
+    use crate::rejection::Rejection;
     use crate::transaction::Transaction;
 
     #[test]
     fn withdrawal_to_non_existent_client_does_not_create_account() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::withdrawal(1, 1, 1.0));
-        assert!(engine.accounts.is_empty());
+        assert_eq!(
+            Err(Rejection::UnknownClient),
+            engine.handle_transaction(Transaction::withdrawal(1, 1, 1.0))
+        );
+        assert!(engine.account_count() == 0);
     }
 
     #[test]
     fn deposit_and_withdrawal_reduces_available() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 2.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 2.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((2.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::withdrawal(1, 2, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::withdrawal(1, 2, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
     }
 
@@ -187,12 +632,17 @@ mod test_withdrawal {
     fn withdrawal_over_available_does_nothing() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::withdrawal(1, 2, 2.0));
-        assert_eq!(1, engine.accounts.len());
+        assert_eq!(
+            Err(Rejection::InsufficientFunds),
+            engine.handle_transaction(Transaction::withdrawal(1, 2, 2.0))
+        );
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
     }
 }
@@ -200,18 +650,23 @@ mod test_withdrawal {
 #[cfg(test)]
 mod test_dispute {
     use crate::engine::Engine;
+    use crate::rejection::Rejection;
     use crate::transaction::Transaction;
 
     #[test]
     fn deposit_and_dispute_reduces_available_and_increases_held() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
     }
 
@@ -219,16 +674,22 @@ mod test_dispute {
     fn withdrawal_and_dispute_increases_available_and_decreases_held() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 2.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 2.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((2.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::withdrawal(1, 2, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::withdrawal(1, 2, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 2));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 2))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((2.0, -1.0), engine.available_and_held_for_client(1));
     }
 
@@ -236,12 +697,17 @@ mod test_dispute {
     fn disputing_a_non_existent_transaction_does_nothing() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 2));
-        assert_eq!(1, engine.accounts.len());
+        assert_eq!(
+            Err(Rejection::UnknownTransaction),
+            engine.handle_transaction(Transaction::dispute(1, 2))
+        );
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
     }
 
@@ -249,12 +715,54 @@ mod test_dispute {
     fn disputing_a_transaction_against_incorrect_client_does_nothing() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
+        assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
+
+        assert_eq!(
+            Err(Rejection::ClientMismatch),
+            engine.handle_transaction(Transaction::dispute(2, 1))
+        );
+        assert_eq!(1, engine.account_count());
+        assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn dispute_with_amount_is_malformed() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+
+        assert_eq!(
+            Err(Rejection::MalformedAmount),
+            engine.handle_transaction(Transaction {
+                amount: Transaction::deposit(1, 1, 1.0).amount,
+                ..Transaction::dispute(1, 1)
+            })
+        );
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
+    }
 
-        engine.handle_transaction(Transaction::dispute(2, 1));
-        assert_eq!(1, engine.accounts.len());
+    #[test]
+    fn disputing_a_rejected_transaction_does_nothing() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::InsufficientFunds),
+            engine.handle_transaction(Transaction::withdrawal(1, 2, 2.0))
+        );
+
+        assert_eq!(
+            Err(Rejection::NotApplied),
+            engine.handle_transaction(Transaction::dispute(1, 2))
+        );
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
     }
 
@@ -262,16 +770,23 @@ mod test_dispute {
     fn transaction_can_only_be_disputed_once() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        assert_eq!(
+            Err(Rejection::AlreadyDisputed),
+            engine.handle_transaction(Transaction::dispute(1, 1))
+        );
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
     }
 }
@@ -279,22 +794,29 @@ mod test_dispute {
 #[cfg(test)]
 mod test_resolve {
     use crate::engine::Engine;
+    use crate::rejection::Rejection;
     use crate::transaction::Transaction;
 
     #[test]
     fn deposit_dispute_and_resolve_releases_held_funds() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::resolve(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::resolve(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
     }
 
@@ -302,20 +824,28 @@ mod test_resolve {
     fn withdrawal_dispute_and_resolve_releases_held_funds() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::withdrawal(1, 2, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::withdrawal(1, 2, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 2));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 2))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, -1.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::resolve(1, 2));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::resolve(1, 2))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 0.0), engine.available_and_held_for_client(1));
     }
 
@@ -323,16 +853,23 @@ mod test_resolve {
     fn resolving_a_non_existent_transaction_does_nothing() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::resolve(1, 2));
-        assert_eq!(1, engine.accounts.len());
+        assert_eq!(
+            Err(Rejection::UnknownTransaction),
+            engine.handle_transaction(Transaction::resolve(1, 2))
+        );
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
     }
 
@@ -340,16 +877,23 @@ mod test_resolve {
     fn resolving_a_transaction_against_incorrect_client_does_nothing() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::resolve(2, 1));
-        assert_eq!(1, engine.accounts.len());
+        assert_eq!(
+            Err(Rejection::ClientMismatch),
+            engine.handle_transaction(Transaction::resolve(2, 1))
+        );
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
     }
 
@@ -357,20 +901,29 @@ mod test_resolve {
     fn dispute_can_only_be_resolved_once() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::resolve(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::resolve(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::resolve(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        assert_eq!(
+            Err(Rejection::NotDisputed),
+            engine.handle_transaction(Transaction::resolve(1, 1))
+        );
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
     }
 }
@@ -381,20 +934,754 @@ mod test_chargeback {
     use crate::transaction::Transaction;
 
     #[test]
-    fn deposit_dispute_and_chargeback_releases_held_funds_and_locks_account() {
+    fn deposit_dispute_and_chargeback_withdraws_held_funds_and_locks_account() {
         let mut engine = Engine::default();
 
-        engine.handle_transaction(Transaction::deposit(1, 1, 1.0));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::dispute(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
         assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
 
-        engine.handle_transaction(Transaction::chargeback(1, 1));
-        assert_eq!(1, engine.accounts.len());
+        engine
+            .handle_transaction(Transaction::chargeback(1, 1))
+            .unwrap();
+        assert_eq!(1, engine.account_count());
+        assert_eq!((0.0, 0.0), engine.available_and_held_for_client(1));
+        assert!(engine.is_account_locked_for_client(1));
+    }
+
+    #[test]
+    fn withdrawal_dispute_and_chargeback_returns_withdrawn_funds_and_locks_account() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::withdrawal(1, 2, 1.0))
+            .unwrap();
+        assert_eq!((0.0, 0.0), engine.available_and_held_for_client(1));
+
+        engine
+            .handle_transaction(Transaction::dispute(1, 2))
+            .unwrap();
+        assert_eq!((1.0, -1.0), engine.available_and_held_for_client(1));
+
+        engine
+            .handle_transaction(Transaction::chargeback(1, 2))
+            .unwrap();
         assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
         assert!(engine.is_account_locked_for_client(1));
     }
 }
+
+#[cfg(test)]
+mod test_account {
+    use crate::engine::Engine;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn unknown_client_has_no_account() {
+        let engine = Engine::default();
+        assert!(engine.account(1).is_none());
+    }
+
+    #[test]
+    fn deposit_creates_account_for_lookup() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert!(engine.account(1).is_some());
+        assert!(engine.account(2).is_none());
+    }
+}
+
+#[cfg(test)]
+mod test_locked {
+    use crate::config::{Config, LockedAccountPolicy};
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::transaction::Transaction;
+
+    fn engine_with_locked_account(policy: LockedAccountPolicy) -> Engine {
+        let mut engine = Engine::new(Config {
+            locked_account_policy: policy,
+            ..Config::default()
+        });
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 2.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::deposit(1, 2, 1.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::deposit(1, 3, 1.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::dispute(1, 2))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::chargeback(1, 1))
+            .unwrap();
+        assert!(engine.is_account_locked_for_client(1));
+        assert_eq!((1.0, 1.0), engine.available_and_held_for_client(1));
+
+        engine
+    }
+
+    #[test]
+    fn locked_account_rejects_deposits() {
+        let mut engine = engine_with_locked_account(LockedAccountPolicy::default());
+
+        assert_eq!(
+            Err(Rejection::AccountLocked),
+            engine.handle_transaction(Transaction::deposit(1, 4, 1.0))
+        );
+        assert_eq!((1.0, 1.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn locked_account_rejects_withdrawals() {
+        let mut engine = engine_with_locked_account(LockedAccountPolicy::default());
+
+        assert_eq!(
+            Err(Rejection::AccountLocked),
+            engine.handle_transaction(Transaction::withdrawal(1, 4, 1.0))
+        );
+        assert_eq!((1.0, 1.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn locked_account_rejects_new_disputes() {
+        let mut engine = engine_with_locked_account(LockedAccountPolicy::default());
+
+        assert_eq!(
+            Err(Rejection::AccountLocked),
+            engine.handle_transaction(Transaction::dispute(1, 3))
+        );
+        assert_eq!((1.0, 1.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn locked_account_settles_in_flight_disputes_by_default() {
+        let mut engine = engine_with_locked_account(LockedAccountPolicy::default());
+
+        engine
+            .handle_transaction(Transaction::resolve(1, 2))
+            .unwrap();
+        assert_eq!((2.0, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn frozen_locked_account_rejects_settling_in_flight_disputes() {
+        let mut engine = engine_with_locked_account(LockedAccountPolicy::Frozen);
+
+        assert_eq!(
+            Err(Rejection::AccountLocked),
+            engine.handle_transaction(Transaction::resolve(1, 2))
+        );
+        assert_eq!(
+            Err(Rejection::AccountLocked),
+            engine.handle_transaction(Transaction::chargeback(1, 2))
+        );
+        assert_eq!((1.0, 1.0), engine.available_and_held_for_client(1));
+    }
+}
+
+#[cfg(test)]
+mod test_duplicate {
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn replayed_deposit_is_rejected() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine.handle_transaction(Transaction::deposit(1, 1, 1.0))
+        );
+        assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn transaction_id_cannot_be_reused_by_another_client() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine.handle_transaction(Transaction::deposit(2, 1, 1.0))
+        );
+        assert!(engine.account(2).is_none());
+    }
+
+    #[test]
+    fn transaction_id_of_rejected_withdrawal_cannot_be_reused() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::InsufficientFunds),
+            engine.handle_transaction(Transaction::withdrawal(1, 2, 2.0))
+        );
+        engine
+            .handle_transaction(Transaction::deposit(1, 3, 1.0))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine.handle_transaction(Transaction::withdrawal(1, 2, 2.0))
+        );
+        assert_eq!((2.0, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn dispute_after_replay_holds_the_original_amount() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine.handle_transaction(Transaction::withdrawal(1, 1, 1.0))
+        );
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
+    }
+}
+
+#[cfg(test)]
+mod test_transaction_state {
+    use crate::config::{Config, RedisputePolicy};
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::state::TransactionState;
+    use crate::transaction::{Transaction, TransactionID};
+
+    fn state_of(engine: &Engine, transaction_id: TransactionID) -> TransactionState {
+        engine.transaction(transaction_id).unwrap().state
+    }
+
+    #[test]
+    fn transaction_moves_through_dispute_lifecycle() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(TransactionState::Processed, state_of(&engine, 1));
+
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(TransactionState::Disputed, state_of(&engine, 1));
+
+        engine
+            .handle_transaction(Transaction::chargeback(1, 1))
+            .unwrap();
+        assert_eq!(TransactionState::ChargedBack, state_of(&engine, 1));
+    }
+
+    #[test]
+    fn rejected_transaction_is_declined() {
+        let mut engine = Engine::default();
+
+        assert_eq!(
+            Err(Rejection::UnknownClient),
+            engine.handle_transaction(Transaction::withdrawal(1, 1, 1.0))
+        );
+        assert_eq!(TransactionState::Declined, state_of(&engine, 1));
+    }
+
+    #[test]
+    fn resolved_transaction_cannot_be_disputed_again_by_default() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::resolve(1, 1))
+            .unwrap();
+
+        assert_eq!(
+            Err(Rejection::DisputeClosed),
+            engine.handle_transaction(Transaction::dispute(1, 1))
+        );
+        assert_eq!(TransactionState::Resolved, state_of(&engine, 1));
+        assert_eq!((1.0, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn resolved_transaction_can_be_disputed_again_when_allowed() {
+        let mut engine = Engine::new(Config {
+            redispute_policy: RedisputePolicy::AfterResolve,
+            ..Config::default()
+        });
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::resolve(1, 1))
+            .unwrap();
+
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(TransactionState::Disputed, state_of(&engine, 1));
+        assert_eq!((0.0, 1.0), engine.available_and_held_for_client(1));
+    }
+}
+
+#[cfg(test)]
+mod test_precision {
+    use crate::config::{Config, ExcessPrecisionPolicy, Precision};
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::transaction::Transaction;
+    use rust_decimal::RoundingStrategy;
+
+    fn engine_rounding_with(strategy: RoundingStrategy) -> Engine {
+        Engine::new(Config {
+            precision: Precision {
+                excess_precision_policy: ExcessPrecisionPolicy::Round(strategy),
+                ..Precision::default()
+            },
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn excess_precision_is_rejected_by_default() {
+        let mut engine = Engine::default();
+
+        assert_eq!(
+            Err(Rejection::ExcessPrecision),
+            engine.handle_transaction(Transaction::deposit(1, 1, 1.00001))
+        );
+        assert!(engine.account_count() == 0);
+        assert!(engine.transaction(1).is_none());
+    }
+
+    #[test]
+    fn excess_precision_is_rounded_when_configured() {
+        let mut engine = engine_rounding_with(RoundingStrategy::MidpointNearestEven);
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.00005))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::withdrawal(1, 2, 0.00015))
+            .unwrap();
+        assert_eq!((0.9998, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn amount_rounded_to_zero_is_rejected() {
+        let mut engine = engine_rounding_with(RoundingStrategy::ToZero);
+
+        assert_eq!(
+            Err(Rejection::ExcessPrecision),
+            engine.handle_transaction(Transaction::deposit(1, 1, 0.00009))
+        );
+        assert!(engine.account_count() == 0);
+    }
+
+    #[test]
+    fn output_has_exactly_configured_decimal_places() {
+        let mut engine = Engine::new(Config {
+            precision: Precision {
+                decimal_places: 2,
+                ..Precision::default()
+            },
+            ..Config::default()
+        });
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        let output = engine.output().next().unwrap();
+        assert_eq!("1.00", output.available.to_string());
+        assert_eq!("0.00", output.held.to_string());
+        assert_eq!("1.00", output.total.to_string());
+    }
+}
+
+#[cfg(test)]
+mod test_output_order {
+    use crate::config::Config;
+    use crate::engine::Engine;
+    use crate::output::OutputOrder;
+    use crate::transaction::{ClientID, Transaction};
+
+    fn output_clients(order: OutputOrder) -> Vec<ClientID> {
+        let mut engine = Engine::new(Config {
+            output_order: order,
+            ..Config::default()
+        });
+
+        for (client, tx, amount) in [(3, 1, 1.0), (1, 2, 3.0), (4, 3, 2.0), (2, 4, 1.0)] {
+            engine
+                .handle_transaction(Transaction::deposit(client, tx, amount))
+                .unwrap();
+        }
+        engine
+            .handle_transaction(Transaction::dispute(4, 3))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::chargeback(4, 3))
+            .unwrap();
+
+        engine.output().map(|row| row.client).collect()
+    }
+
+    #[test]
+    fn output_is_ordered_by_client_by_default() {
+        assert_eq!(vec![1, 2, 3, 4], output_clients(OutputOrder::default()));
+    }
+
+    #[test]
+    fn output_can_be_ordered_by_total() {
+        assert_eq!(vec![4, 2, 3, 1], output_clients(OutputOrder::Total));
+    }
+
+    #[test]
+    fn output_can_be_ordered_locked_first() {
+        assert_eq!(vec![4, 1, 2, 3], output_clients(OutputOrder::LockedFirst));
+    }
+}
+
+#[cfg(test)]
+mod test_dispute_window {
+    use crate::config::{Config, DisputeWindow};
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::transaction::Transaction;
+
+    fn engine_with_window(transactions: Option<u64>, millis: Option<u64>) -> Engine {
+        Engine::new(Config {
+            dispute_window: DisputeWindow {
+                transactions,
+                millis,
+            },
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn dispute_within_transaction_window_is_applied() {
+        let mut engine = engine_with_window(Some(2), None);
+
+        for tx in 1..=2 {
+            engine
+                .handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap();
+        }
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::dispute(1, 1))
+        );
+    }
+
+    #[test]
+    fn dispute_once_transaction_window_is_reached_is_rejected() {
+        let mut engine = engine_with_window(Some(2), None);
+
+        for tx in 1..=3 {
+            engine
+                .handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap();
+        }
+        assert_eq!(
+            Err(Rejection::DisputeExpired),
+            engine.handle_transaction(Transaction::dispute(1, 1))
+        );
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::dispute(1, 2))
+        );
+    }
+
+    #[test]
+    fn dispute_outside_transaction_window_is_rejected() {
+        let mut engine = engine_with_window(Some(2), None);
+
+        for tx in 1..=4 {
+            engine
+                .handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap();
+        }
+        assert_eq!(
+            Err(Rejection::DisputeExpired),
+            engine.handle_transaction(Transaction::dispute(1, 1))
+        );
+        assert_eq!((4.0, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn other_clients_do_not_count_towards_the_window() {
+        let mut engine = engine_with_window(Some(1), None);
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        for tx in 2..=10 {
+            engine
+                .handle_transaction(Transaction::deposit(2, tx, 1.0))
+                .unwrap();
+        }
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::dispute(1, 1))
+        );
+    }
+
+    #[test]
+    fn dispute_outside_time_window_is_rejected() {
+        let mut engine = engine_with_window(None, Some(1000));
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0).at(5000))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::deposit(1, 2, 1.0).at(5500))
+            .unwrap();
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::dispute(1, 2).at(6500))
+        );
+        assert_eq!(
+            Err(Rejection::DisputeExpired),
+            engine.handle_transaction(Transaction::dispute(1, 1).at(6500))
+        );
+    }
+
+    #[test]
+    fn rejected_transactions_do_not_count_towards_time_window() {
+        let mut engine = engine_with_window(None, Some(1000));
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0).at(5000))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::InsufficientFunds),
+            engine.handle_transaction(Transaction::withdrawal(1, 2, 5.0).at(9000))
+        );
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::dispute(1, 1).at(5500))
+        );
+    }
+
+    #[test]
+    fn transactions_without_timestamps_are_not_bound_by_time() {
+        let mut engine = engine_with_window(None, Some(1000));
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::dispute(1, 1).at(1_000_000))
+        );
+    }
+
+    #[test]
+    fn expired_transactions_are_evicted_but_keep_their_id() {
+        let mut engine = engine_with_window(Some(2), None);
+
+        for tx in 1..=3 {
+            engine
+                .handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap();
+        }
+        engine.evict_expired();
+
+        assert!(engine.transaction(1).is_none());
+        assert!(engine.transaction(2).is_some());
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine.handle_transaction(Transaction::deposit(1, 1, 1.0))
+        );
+        assert_eq!(
+            Err(Rejection::DisputeExpired),
+            engine.handle_transaction(Transaction::dispute(1, 1))
+        );
+    }
+
+    #[test]
+    fn disputed_transactions_are_never_evicted() {
+        let mut engine = engine_with_window(Some(1), None);
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0))
+            .unwrap();
+        engine
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        for tx in 2..=5 {
+            engine
+                .handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap();
+        }
+        engine.evict_expired();
+
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::resolve(1, 1))
+        );
+        assert_eq!((5.0, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn eviction_happens_as_transactions_are_handled() {
+        let mut engine = engine_with_window(Some(10), None);
+
+        for tx in 1..=10_000 {
+            engine
+                .handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap();
+        }
+        assert!(engine.transaction(1).is_none());
+        assert!(engine.transaction(10_000).is_some());
+    }
+
+    #[test]
+    fn evicted_ids_survive_a_snapshot() {
+        let mut engine = engine_with_window(Some(2), None);
+        for tx in 1..=3 {
+            engine
+                .handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap();
+        }
+        engine.evict_expired();
+
+        let mut restored = Engine::restore(Config::default(), engine.snapshot()).unwrap();
+        assert_eq!(vec![(1, 1)], restored.snapshot().evicted);
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            restored.handle_transaction(Transaction::deposit(1, 1, 1.0))
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_timestamp_order {
+    use crate::config::{Config, TimestampOrder};
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn out_of_order_timestamps_are_applied_by_default() {
+        let mut engine = Engine::default();
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0).at(2000))
+            .unwrap();
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::deposit(1, 2, 1.0).at(1000))
+        );
+    }
+
+    #[test]
+    fn out_of_order_timestamps_are_rejected_when_monotonic() {
+        let mut engine = Engine::new(Config {
+            timestamp_order: TimestampOrder::Monotonic,
+            ..Config::default()
+        });
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0).at(2000))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::OutOfOrder),
+            engine.handle_transaction(Transaction::deposit(1, 2, 1.0).at(1000))
+        );
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::deposit(1, 3, 1.0).at(2000))
+        );
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::deposit(1, 4, 1.0))
+        );
+        assert_eq!((3.0, 0.0), engine.available_and_held_for_client(1));
+    }
+
+    #[test]
+    fn out_of_order_deposits_reserve_their_id() {
+        let mut engine = Engine::new(Config {
+            timestamp_order: TimestampOrder::Monotonic,
+            ..Config::default()
+        });
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0).at(2000))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::OutOfOrder),
+            engine.handle_transaction(Transaction::deposit(1, 2, 1.0).at(1000))
+        );
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine.handle_transaction(Transaction::deposit(1, 2, 1.0).at(2000))
+        );
+    }
+
+    #[test]
+    fn rejected_transactions_do_not_move_the_clock() {
+        let mut engine = Engine::new(Config {
+            timestamp_order: TimestampOrder::Monotonic,
+            ..Config::default()
+        });
+
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0).at(1000))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::InsufficientFunds),
+            engine.handle_transaction(Transaction::withdrawal(1, 2, 5.0).at(9000))
+        );
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine.handle_transaction(Transaction::deposit(1, 1, 1.0).at(9000))
+        );
+        assert_eq!(
+            Ok(()),
+            engine.handle_transaction(Transaction::deposit(1, 3, 1.0).at(2000))
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use crate::input::InputError;
+use crate::output::AccountOutput;
+use crate::rejection::Rejection;
+use crate::store::{MemoryStore, Store};
+use crate::stream::EngineHandle;
+use crate::transaction::{ClientID, PositiveAmount, Timestamp, Transaction, TransactionType};
+use proto::engine_server::EngineServer;
+use proto::outcome::Status as OutcomeStatus;
+use rust_decimal::Decimal;
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio_stream::wrappers::{TcpListenerStream, WatchStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+/// The messages and services generated from `proto/engine.proto`.
+pub mod proto {
+    tonic::include_proto!("toy_engine");
+}
+
+/// The `Engine` gRPC service, applying transactions to the engine behind an [`EngineHandle`].
+pub struct GrpcEngine<S = MemoryStore> {
+    handle: EngineHandle<S>,
+}
+
+impl<S: Store + Send + 'static> GrpcEngine<S> {
+    pub fn new(handle: EngineHandle<S>) -> Self {
+        Self { handle }
+    }
+
+    /// Wraps the service for adding to a [`tonic::transport::Server`].
+    pub fn into_server(self) -> EngineServer<Self> {
+        EngineServer::new(self)
+    }
+}
+
+/// Serves the `Engine` gRPC service on `listener` until the server fails.
+pub async fn serve<S: Store + Send + 'static>(
+    listener: tokio::net::TcpListener,
+    handle: EngineHandle<S>,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(GrpcEngine::new(handle).into_server())
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
+
+#[tonic::async_trait]
+impl<S: Store + Send + 'static> proto::engine_server::Engine for GrpcEngine<S> {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::Outcome>, Status> {
+        let outcome = match Transaction::try_from(request.into_inner()) {
+            Ok(transaction) => self
+                .handle
+                .submit(transaction)
+                .await
+                .map_err(internal)?
+                .into(),
+            Err(error) => error.into(),
+        };
+        Ok(Response::new(outcome))
+    }
+
+    async fn submit_batch(
+        &self,
+        request: Request<Streaming<proto::Transaction>>,
+    ) -> Result<Response<proto::BatchOutcome>, Status> {
+        // Each transaction is queued as it arrives, so the engine's queue holds the stream back.
+        let mut messages = request.into_inner();
+        let mut verdicts = Vec::new();
+        while let Some(message) = messages.message().await? {
+            verdicts.push(match Transaction::try_from(message) {
+                Ok(transaction) => Ok(self.handle.queue(transaction).await.map_err(internal)?),
+                Err(error) => Err(error),
+            });
+        }
+
+        let mut outcomes = Vec::with_capacity(verdicts.len());
+        for verdict in verdicts {
+            outcomes.push(match verdict {
+                Ok(verdict) => verdict.wait().await.map_err(internal)?.into(),
+                Err(error) => error.into(),
+            });
+        }
+        Ok(Response::new(proto::BatchOutcome { outcomes }))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let client = client_id(request.into_inner().client)?;
+        self.handle
+            .read(move |engine| anyhow::Ok(engine.try_account_output(client)?))
+            .await
+            .and_then(|account| account)
+            .map_err(internal)?
+            .map(|account| Response::new(account.into()))
+            .ok_or_else(|| Status::not_found(Rejection::UnknownClient.to_string()))
+    }
+
+    type WatchAccountStream = Pin<Box<dyn Stream<Item = Result<proto::Account, Status>> + Send>>;
+
+    async fn watch_account(
+        &self,
+        request: Request<proto::WatchAccountRequest>,
+    ) -> Result<Response<Self::WatchAccountStream>, Status> {
+        let client = client_id(request.into_inner().client)?;
+        let watch = self.handle.watch(client).await.map_err(internal)?;
+        let accounts =
+            WatchStream::new(watch).filter_map(|account| account.map(|account| Ok(account.into())));
+        Ok(Response::new(Box::pin(accounts)))
+    }
+}
+
+impl TryFrom<proto::Transaction> for Transaction {
+    type Error = InputError;
+
+    fn try_from(message: proto::Transaction) -> Result<Self, InputError> {
+        let r#type = match proto::TransactionType::try_from(message.r#type) {
+            Ok(proto::TransactionType::Deposit) => TransactionType::Deposit,
+            Ok(proto::TransactionType::Withdrawal) => TransactionType::Withdrawal,
+            Ok(proto::TransactionType::Dispute) => TransactionType::Dispute,
+            Ok(proto::TransactionType::Resolve) => TransactionType::Resolve,
+            Ok(proto::TransactionType::Chargeback) => TransactionType::Chargeback,
+            Ok(proto::TransactionType::Unspecified) | Err(_) => {
+                return Err(invalid_field("type", "expected a transaction type"));
+            }
+        };
+        let client = ClientID::try_from(message.client)
+            .map_err(|_| invalid_field("client", "client must be at most 65535"))?;
+        let amount = message
+            .amount
+            .map(|amount| {
+                let amount =
+                    Decimal::from_str(&amount).map_err(|error| invalid_field("amount", error))?;
+                PositiveAmount::try_from(amount).map_err(|error| invalid_field("amount", error))
+            })
+            .transpose()?;
+
+        Ok(Transaction {
+            r#type,
+            client,
+            tx: message.tx,
+            amount,
+            timestamp: message.timestamp.map(Timestamp::from_millis),
+        })
+    }
+}
+
+impl From<Result<(), Rejection>> for proto::Outcome {
+    fn from(verdict: Result<(), Rejection>) -> Self {
+        match verdict {
+            Ok(()) => proto::Outcome {
+                status: OutcomeStatus::Ok.into(),
+                reason: None,
+            },
+            Err(rejection) => proto::Outcome {
+                status: OutcomeStatus::Rejected.into(),
+                reason: Some(proto::Reason {
+                    code: rejection.code().to_string(),
+                    message: rejection.to_string(),
+                }),
+            },
+        }
+    }
+}
+
+impl From<InputError> for proto::Outcome {
+    fn from(error: InputError) -> Self {
+        proto::Outcome {
+            status: OutcomeStatus::Malformed.into(),
+            reason: Some(proto::Reason {
+                code: error.code().to_string(),
+                message: error.to_string(),
+            }),
+        }
+    }
+}
+
+impl From<AccountOutput> for proto::Account {
+    fn from(account: AccountOutput) -> Self {
+        proto::Account {
+            client: account.client.into(),
+            available: account.available.to_string(),
+            held: account.held.to_string(),
+            total: account.total.to_string(),
+            locked: account.locked,
+        }
+    }
+}
+
+fn invalid_field(field: &str, message: impl ToString) -> InputError {
+    InputError::InvalidField {
+        field: field.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn client_id(client: u32) -> Result<ClientID, Status> {
+    ClientID::try_from(client).map_err(|_| Status::invalid_argument("client must be at most 65535"))
+}
+
+fn internal(error: anyhow::Error) -> Status {
+    Status::internal(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::grpc::proto::engine_client::EngineClient;
+    use crate::grpc::proto::outcome::Status as OutcomeStatus;
+    use crate::grpc::proto::{
+        GetAccountRequest, Transaction, TransactionType, WatchAccountRequest,
+    };
+    use crate::grpc::serve;
+    use crate::stream::{AsyncEngine, DEFAULT_CAPACITY};
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::Code;
+    use tonic::transport::Channel;
+
+    /// Runs `test` against a client of a server listening on a free local port.
+    fn with_client(test: impl AsyncFnOnce(EngineClient<Channel>)) {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let engine = AsyncEngine::spawn(Engine::default(), DEFAULT_CAPACITY);
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let address = listener.local_addr().unwrap();
+                tokio::spawn(serve(listener, engine.handle()));
+                let client = EngineClient::connect(format!("http://{address}"))
+                    .await
+                    .unwrap();
+                test(client).await;
+            });
+    }
+
+    fn transaction(
+        r#type: TransactionType,
+        client: u32,
+        tx: u32,
+        amount: Option<&str>,
+    ) -> Transaction {
+        Transaction {
+            r#type: r#type.into(),
+            client,
+            tx,
+            amount: amount.map(String::from),
+            timestamp: None,
+        }
+    }
+
+    fn deposit(client: u32, tx: u32, amount: &str) -> Transaction {
+        transaction(TransactionType::Deposit, client, tx, Some(amount))
+    }
+
+    #[test]
+    fn submitted_transactions_answer_with_their_outcome() {
+        with_client(async |mut client| {
+            let outcome = client
+                .submit_transaction(deposit(1, 1, "2.5"))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(OutcomeStatus::Ok, outcome.status());
+            assert_eq!(None, outcome.reason);
+
+            let withdrawal = transaction(TransactionType::Withdrawal, 1, 2, Some("5"));
+            let outcome = client
+                .submit_transaction(withdrawal)
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(OutcomeStatus::Rejected, outcome.status());
+            assert_eq!("insufficient_funds", outcome.reason.unwrap().code);
+
+            let outcome = client
+                .submit_transaction(deposit(70_000, 3, "1"))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(OutcomeStatus::Malformed, outcome.status());
+            assert_eq!("invalid_field", outcome.reason.unwrap().code);
+        });
+    }
+
+    #[test]
+    fn batches_answer_with_every_outcome_in_order() {
+        with_client(async |mut client| {
+            let batch = vec![
+                deposit(1, 1, "1"),
+                deposit(1, 2, "-1"),
+                deposit(1, 1, "1"),
+                transaction(TransactionType::Dispute, 1, 1, None),
+            ];
+            let outcomes = client
+                .submit_batch(tokio_stream::iter(batch))
+                .await
+                .unwrap()
+                .into_inner()
+                .outcomes;
+
+            let statuses: Vec<_> = outcomes.iter().map(|outcome| outcome.status()).collect();
+            assert_eq!(
+                vec![
+                    OutcomeStatus::Ok,
+                    OutcomeStatus::Malformed,
+                    OutcomeStatus::Rejected,
+                    OutcomeStatus::Ok
+                ],
+                statuses
+            );
+        });
+    }
+
+    #[test]
+    fn batches_are_applied_as_they_arrive() {
+        with_client(async |mut client| {
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            let batch = tokio::spawn({
+                let mut client = client.clone();
+                async move { client.submit_batch(ReceiverStream::new(receiver)).await }
+            });
+            sender.send(deposit(1, 1, "1")).await.unwrap();
+
+            // The deposit is applied while the batch is still open.
+            let mut applied = false;
+            for _ in 0..1000 {
+                if client
+                    .get_account(GetAccountRequest { client: 1 })
+                    .await
+                    .is_ok()
+                {
+                    applied = true;
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+            assert!(applied);
+
+            drop(sender);
+            let outcomes = batch.await.unwrap().unwrap().into_inner().outcomes;
+            assert_eq!(OutcomeStatus::Ok, outcomes[0].status());
+        });
+    }
+
+    #[test]
+    fn accounts_are_looked_up_by_client() {
+        with_client(async |mut client| {
+            client
+                .submit_transaction(deposit(3, 1, "1.5"))
+                .await
+                .unwrap();
+
+            let account = client
+                .get_account(GetAccountRequest { client: 3 })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(
+                (3, "1.5000", "0.0000", "1.5000", false),
+                (
+                    account.client,
+                    account.available.as_str(),
+                    account.held.as_str(),
+                    account.total.as_str(),
+                    account.locked
+                )
+            );
+            let missing = client.get_account(GetAccountRequest { client: 4 }).await;
+            assert_eq!(Code::NotFound, missing.unwrap_err().code());
+        });
+    }
+
+    #[test]
+    fn watchers_are_sent_every_change_to_their_client() {
+        with_client(async |mut client| {
+            let mut watch = client
+                .watch_account(WatchAccountRequest { client: 1 })
+                .await
+                .unwrap()
+                .into_inner();
+
+            client.submit_transaction(deposit(1, 1, "1")).await.unwrap();
+            assert_eq!("1.0000", watch.message().await.unwrap().unwrap().total);
+
+            client.submit_transaction(deposit(2, 2, "5")).await.unwrap();
+            let dispute = transaction(TransactionType::Dispute, 1, 1, None);
+            client.submit_transaction(dispute).await.unwrap();
+            let account = watch.message().await.unwrap().unwrap();
+            assert_eq!(
+                ("0.0000", "1.0000"),
+                (account.available.as_str(), account.held.as_str())
+            );
+        });
+    }
+}
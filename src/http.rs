@@ -0,0 +1,476 @@
+use crate::input::{InputError, json_transaction};
+use crate::output::AccountOutput;
+use crate::rejection::Rejection;
+use crate::snapshot::TransactionSnapshot;
+use crate::store::Store;
+use crate::stream::EngineHandle;
+use crate::transaction::{ClientID, TransactionID};
+use axum::body::Bytes;
+use axum::extract::rejection::{PathRejection, QueryRejection};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::serve::Serve;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How many accounts a page holds when the request does not say.
+pub const DEFAULT_PAGE_LEN: usize = 100;
+/// The most accounts a page can hold.
+pub const MAX_PAGE_LEN: usize = 1000;
+
+/// Routes a JSON API onto the engine behind `handle`:
+///
+/// - `POST /transactions` applies a transaction object, or an array of them in order, and
+///   answers with the outcome of each.
+/// - `GET /accounts/{client}` answers with the client's account, as [`AccountOutput`].
+/// - `GET /accounts?after={client}&limit={count}` answers with a page of accounts in client
+///   order, and the `next` value of `after` while more remain.
+/// - `GET /transactions/{tx}` answers with a stored deposit or withdrawal and its dispute
+///   state, as [`TransactionSnapshot`].
+///
+/// Anything that fails is answered with an `error` holding a `code`, such as
+/// `unknown_client`, and a `message`.
+pub fn router<S: Store + Send + 'static>(handle: EngineHandle<S>) -> Router {
+    Router::new()
+        .route("/transactions", post(submit::<S>))
+        .route("/transactions/{tx}", get(transaction::<S>))
+        .route("/accounts", get(accounts::<S>))
+        .route("/accounts/{client}", get(account::<S>))
+        .with_state(handle)
+}
+
+/// Serves the [`router`] on `listener` until the server fails.
+pub fn serve<S: Store + Send + 'static>(
+    listener: tokio::net::TcpListener,
+    handle: EngineHandle<S>,
+) -> Serve<tokio::net::TcpListener, Router, Router> {
+    axum::serve(listener, router(handle))
+}
+
+/// Why a request failed, or a transaction had no effect.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reason {
+    /// A stable, machine-readable name, as [`Rejection::code`] or [`InputError::code`].
+    pub code: String,
+    pub message: String,
+}
+
+/// What became of one submitted transaction.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    /// The engine rejected the transaction.
+    Rejected {
+        reason: Reason,
+    },
+    /// The transaction could not be read, so never reached the engine.
+    Malformed {
+        reason: Reason,
+    },
+}
+
+/// A page of accounts from `GET /accounts`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AccountPage {
+    pub accounts: Vec<AccountOutput>,
+    /// The `after` to ask for the next page with, if there are more accounts.
+    pub next: Option<ClientID>,
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    after: Option<ClientID>,
+    limit: Option<usize>,
+}
+
+/// A failed request, answered as `{"error": {"code": ..., "message": ...}}`.
+struct ApiError {
+    status: StatusCode,
+    reason: Reason,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: Reason,
+}
+
+async fn submit<S: Store + Send + 'static>(
+    State(handle): State<EngineHandle<S>>,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let value: Value = serde_json::from_slice(&body)
+        .map_err(|error| ApiError::new(StatusCode::BAD_REQUEST, "invalid_json", error))?;
+
+    let Value::Array(values) = value else {
+        let outcome = match json_transaction(value) {
+            Ok(transaction) => Outcome::from(handle.submit(transaction).await?),
+            Err(error) => Outcome::from(error),
+        };
+        let status = match outcome {
+            Outcome::Ok => StatusCode::OK,
+            Outcome::Rejected { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Outcome::Malformed { .. } => StatusCode::BAD_REQUEST,
+        };
+        return Ok((status, Json(outcome)).into_response());
+    };
+
+    let mut transactions = Vec::with_capacity(values.len());
+    let errors: Vec<_> = values
+        .into_iter()
+        .map(|value| match json_transaction(value) {
+            Ok(transaction) => {
+                transactions.push(transaction);
+                None
+            }
+            Err(error) => Some(error),
+        })
+        .collect();
+    let mut verdicts = handle.submit_batch(transactions).await?.into_iter();
+    let outcomes: Vec<_> = errors
+        .into_iter()
+        .map(|error| match error {
+            Some(error) => Outcome::from(error),
+            None => Outcome::from(verdicts.next().expect("a verdict for every transaction")),
+        })
+        .collect();
+    Ok(Json(outcomes).into_response())
+}
+
+async fn account<S: Store + Send + 'static>(
+    State(handle): State<EngineHandle<S>>,
+    client: Result<Path<ClientID>, PathRejection>,
+) -> Result<Json<AccountOutput>, ApiError> {
+    let Path(client) = client?;
+    handle
+        .read(move |engine| anyhow::Ok(engine.try_account_output(client)?))
+        .await??
+        .map(Json)
+        .ok_or_else(|| ApiError::rejection(StatusCode::NOT_FOUND, Rejection::UnknownClient))
+}
+
+async fn accounts<S: Store + Send + 'static>(
+    State(handle): State<EngineHandle<S>>,
+    page: Result<Query<PageQuery>, QueryRejection>,
+) -> Result<Json<AccountPage>, ApiError> {
+    let Query(PageQuery { after, limit }) = page?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LEN).clamp(1, MAX_PAGE_LEN);
+
+    let mut accounts: Vec<_> = handle
+        .read(move |engine| {
+            let rows = engine.try_output()?;
+            anyhow::Ok(
+                rows.filter(|row| after.is_none_or(|after| row.client > after))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await??;
+    // The configured output order is for whole outputs, pages always follow client order.
+    accounts.sort_by_key(|account| account.client);
+    let next = (accounts.len() > limit).then(|| accounts[limit - 1].client);
+    accounts.truncate(limit);
+
+    Ok(Json(AccountPage { accounts, next }))
+}
+
+async fn transaction<S: Store + Send + 'static>(
+    State(handle): State<EngineHandle<S>>,
+    tx: Result<Path<TransactionID>, PathRejection>,
+) -> Result<Json<TransactionSnapshot>, ApiError> {
+    let Path(tx) = tx?;
+    let (transaction, evicted) = handle
+        .read(move |engine| {
+            let store = engine.store();
+            anyhow::Ok((store.transaction(tx)?, store.is_evicted(tx)?))
+        })
+        .await??;
+
+    match transaction {
+        Some(transaction) => Ok(Json((tx, transaction).into())),
+        None if evicted => Err(ApiError::rejection(
+            StatusCode::GONE,
+            Rejection::DisputeExpired,
+        )),
+        None => Err(ApiError::rejection(
+            StatusCode::NOT_FOUND,
+            Rejection::UnknownTransaction,
+        )),
+    }
+}
+
+impl From<Result<(), Rejection>> for Outcome {
+    fn from(verdict: Result<(), Rejection>) -> Self {
+        match verdict {
+            Ok(()) => Outcome::Ok,
+            Err(rejection) => Outcome::Rejected {
+                reason: Reason {
+                    code: rejection.code().to_string(),
+                    message: rejection.to_string(),
+                },
+            },
+        }
+    }
+}
+
+impl From<InputError> for Outcome {
+    fn from(error: InputError) -> Self {
+        Outcome::Malformed {
+            reason: Reason {
+                code: error.code().to_string(),
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &str, message: impl ToString) -> Self {
+        Self {
+            status,
+            reason: Reason {
+                code: code.to_string(),
+                message: message.to_string(),
+            },
+        }
+    }
+
+    fn rejection(status: StatusCode, rejection: Rejection) -> Self {
+        Self::new(status, rejection.code(), rejection)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", error)
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_path",
+            rejection.body_text(),
+        )
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_query",
+            rejection.body_text(),
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody { error: self.reason };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::http::router;
+    use crate::stream::{AsyncEngine, DEFAULT_CAPACITY};
+    use axum::Router;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    fn with_router(test: impl AsyncFnOnce(Router)) {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let engine = AsyncEngine::spawn(Engine::default(), DEFAULT_CAPACITY);
+                test(router(engine.handle())).await;
+            });
+    }
+
+    async fn get(app: &Router, uri: &str) -> (StatusCode, Value) {
+        send(app, Request::get(uri).body(Body::empty()).unwrap()).await
+    }
+
+    async fn post(app: &Router, body: &str) -> (StatusCode, Value) {
+        let request = Request::post("/transactions")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        send(app, request).await
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn single_transactions_answer_with_their_outcome() {
+        with_router(async |app| {
+            assert_eq!(
+                (StatusCode::OK, json!({"status": "ok"})),
+                post(
+                    &app,
+                    r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.0"}"#
+                )
+                .await
+            );
+            assert_eq!(
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    json!({
+                        "status": "rejected",
+                        "reason": {
+                            "code": "insufficient_funds",
+                            "message": "insufficient available funds"
+                        }
+                    })
+                ),
+                post(
+                    &app,
+                    r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": 5}"#
+                )
+                .await
+            );
+            let (status, body) = post(&app, "{").await;
+            assert_eq!(StatusCode::BAD_REQUEST, status);
+            assert_eq!(json!("invalid_json"), body["error"]["code"]);
+        });
+    }
+
+    #[test]
+    fn batches_answer_with_every_outcome_in_order() {
+        with_router(async |app| {
+            let (status, body) = post(
+                &app,
+                r#"[
+                    {"type": "deposit", "client": 1, "tx": 1, "amount": 1},
+                    {"type": "lemon", "client": 1, "tx": 2},
+                    {"type": "deposit", "client": 1, "tx": 1, "amount": 1},
+                    {"type": "dispute", "client": 1, "tx": 1}
+                ]"#,
+            )
+            .await;
+
+            assert_eq!(StatusCode::OK, status);
+            let statuses: Vec<_> = body
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|outcome| outcome["status"].as_str().unwrap())
+                .collect();
+            assert_eq!(vec!["ok", "malformed", "rejected", "ok"], statuses);
+            assert_eq!(json!("invalid_field"), body[1]["reason"]["code"]);
+            assert_eq!(json!("duplicate_transaction"), body[2]["reason"]["code"]);
+        });
+    }
+
+    #[test]
+    fn accounts_are_looked_up_by_client() {
+        with_router(async |app| {
+            post(
+                &app,
+                r#"{"type": "deposit", "client": 3, "tx": 1, "amount": 1.5}"#,
+            )
+            .await;
+
+            assert_eq!(
+                (
+                    StatusCode::OK,
+                    json!({
+                        "client": 3,
+                        "available": "1.5000",
+                        "held": "0.0000",
+                        "total": "1.5000",
+                        "locked": false
+                    })
+                ),
+                get(&app, "/accounts/3").await
+            );
+            let (status, body) = get(&app, "/accounts/4").await;
+            assert_eq!(StatusCode::NOT_FOUND, status);
+            assert_eq!(json!("unknown_client"), body["error"]["code"]);
+            let (status, body) = get(&app, "/accounts/lemon").await;
+            assert_eq!(StatusCode::BAD_REQUEST, status);
+            assert_eq!(json!("invalid_path"), body["error"]["code"]);
+        });
+    }
+
+    #[test]
+    fn accounts_are_paginated_in_client_order() {
+        with_router(async |app| {
+            for client in [5, 1, 4, 2, 3] {
+                let deposit =
+                    json!({"type": "deposit", "client": client, "tx": client, "amount": 1});
+                post(&app, &deposit.to_string()).await;
+            }
+            let clients = |body: &Value| -> Vec<u64> {
+                body["accounts"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|account| account["client"].as_u64().unwrap())
+                    .collect()
+            };
+
+            let (_, first) = get(&app, "/accounts?limit=2").await;
+            assert_eq!(
+                (vec![1, 2], json!(2)),
+                (clients(&first), first["next"].clone())
+            );
+            let (_, second) = get(&app, "/accounts?limit=2&after=2").await;
+            assert_eq!(
+                (vec![3, 4], json!(4)),
+                (clients(&second), second["next"].clone())
+            );
+            let (_, last) = get(&app, "/accounts?limit=2&after=4").await;
+            assert_eq!(
+                (vec![5], Value::Null),
+                (clients(&last), last["next"].clone())
+            );
+            let (status, _) = get(&app, "/accounts?limit=lemon").await;
+            assert_eq!(StatusCode::BAD_REQUEST, status);
+        });
+    }
+
+    #[test]
+    fn transactions_show_their_dispute_state() {
+        with_router(async |app| {
+            post(
+                &app,
+                r#"[
+                    {"type": "deposit", "client": 1, "tx": 7, "amount": 2},
+                    {"type": "dispute", "client": 1, "tx": 7}
+                ]"#,
+            )
+            .await;
+
+            let (status, body) = get(&app, "/transactions/7").await;
+            assert_eq!(StatusCode::OK, status);
+            assert_eq!(
+                (json!(1), json!("2"), json!("disputed")),
+                (
+                    body["client"].clone(),
+                    body["amount"].clone(),
+                    body["state"].clone()
+                )
+            );
+            let (status, body) = get(&app, "/transactions/8").await;
+            assert_eq!(StatusCode::NOT_FOUND, status);
+            assert_eq!(json!("unknown_transaction"), body["error"]["code"]);
+        });
+    }
+}
@@ -0,0 +1,616 @@
+use crate::transaction::{
+    ClientID, PositiveAmount, Timestamp, Transaction, TransactionID, TransactionType,
+};
+use csv::{ByteRecord, DeserializeError};
+use serde::Deserialize;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use thiserror::Error;
+
+const BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// How transactions are encoded in an input.
+///
+/// Every format has the same fields as [`Transaction`]: `type`, `client`, `tx` and `amount`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Comma separated values with a header row.
+    #[default]
+    Csv,
+    /// One JSON object per line.
+    Ndjson,
+    /// A single JSON array of objects.
+    Json,
+}
+
+impl InputFormat {
+    /// Guesses the format from a file extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "csv" => Some(InputFormat::Csv),
+            "ndjson" | "jsonl" => Some(InputFormat::Ndjson),
+            "json" => Some(InputFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Reads rows of transactions in this format.
+    ///
+    /// Only failing to read the input is an error, rows that cannot be deserialized are
+    /// returned with an [`InputError`].
+    pub fn rows<'r>(
+        self,
+        reader: impl Read + 'r,
+    ) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<Row>> + 'r>> {
+        Ok(match self {
+            InputFormat::Csv => Box::new(csv_rows(reader)?),
+            InputFormat::Ndjson => Box::new(ndjson_rows(reader)),
+            InputFormat::Json => Box::new(json_rows(reader)?),
+        })
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> anyhow::Result<Self> {
+        match format {
+            "csv" => Ok(InputFormat::Csv),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            "json" => Ok(InputFormat::Json),
+            _ => anyhow::bail!("expected one of csv, ndjson or json"),
+        }
+    }
+}
+
+/// A single row of input, kept alongside where it came from so it can be reported.
+#[derive(Debug)]
+pub struct Row {
+    /// The line the row starts on, counting any header as line 1. For a JSON array this is
+    /// instead the position of the row in the array.
+    pub line: u64,
+    /// The row as it appeared in the input.
+    pub record: String,
+    pub transaction: Result<Transaction, InputError>,
+}
+
+/// Why a row could not be read as a [`Transaction`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum InputError {
+    #[error("expected {expected} fields but found {found}")]
+    WrongFieldCount { expected: usize, found: usize },
+    #[error("invalid `{field}`: {message}")]
+    InvalidField { field: String, message: String },
+    #[error("invalid record: {0}")]
+    InvalidRecord(String),
+}
+
+impl InputError {
+    /// A stable, machine-readable name for the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            InputError::WrongFieldCount { .. } => "wrong_field_count",
+            InputError::InvalidField { .. } => "invalid_field",
+            InputError::InvalidRecord(_) => "invalid_record",
+        }
+    }
+}
+
+/// Parses rows one line at a time, for inputs such as network streams that arrive a line at a
+/// time rather than through one [`Read`].
+///
+/// A CSV row cannot span lines here, and the first line is taken as the header row unless
+/// headers are given with [`LineParser::with_headers`]. A JSON array is a single value, so it
+/// cannot be parsed this way.
+#[derive(Debug, Clone)]
+pub struct LineParser {
+    format: InputFormat,
+    headers: Option<ByteRecord>,
+    line: u64,
+}
+
+impl LineParser {
+    /// The most bytes a line can hold before its line ending. Streams read for a parser stop
+    /// at this many, so a peer that never ends a line cannot grow memory without bound.
+    pub const MAX_LINE_LEN: usize = 64 * 1024;
+
+    pub fn new(format: InputFormat) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            format != InputFormat::Json,
+            "a JSON array cannot be read a line at a time"
+        );
+        Ok(Self {
+            format,
+            headers: None,
+            line: 0,
+        })
+    }
+
+    /// Parses every CSV line as a row with these comma separated headers, so the first line is
+    /// not taken as the header row.
+    pub fn with_headers(mut self, headers: &str) -> Self {
+        let mut headers = csv_record(headers);
+        headers.trim();
+        self.headers = Some(headers);
+        self
+    }
+
+    /// Parses the next line, returning `None` for a blank line or the header row.
+    pub fn parse(&mut self, line: &str) -> Option<Row> {
+        self.line += 1;
+        let record = line.trim_end_matches(['\r', '\n']);
+        if record.trim().is_empty() {
+            return None;
+        }
+        match self.format {
+            InputFormat::Csv => {
+                let mut raw = csv_record(record);
+                match &self.headers {
+                    Some(headers) => Some(csv_row(headers, self.line, raw)),
+                    None => {
+                        raw.trim();
+                        self.headers = Some(raw);
+                        None
+                    }
+                }
+            }
+            InputFormat::Ndjson | InputFormat::Json => Some(Row {
+                line: self.line,
+                record: record.to_string(),
+                transaction: serde_json::from_str(record)
+                    .map_err(|error| InputError::InvalidRecord(error.to_string()))
+                    .and_then(json_transaction),
+            }),
+        }
+    }
+}
+
+/// Splits a single line of CSV into its fields.
+fn csv_record(line: &str) -> ByteRecord {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(line.as_bytes());
+    let mut record = ByteRecord::new();
+    // Reading from a slice cannot fail, and an unterminated quote runs to the end of the line.
+    let _ = reader.read_byte_record(&mut record);
+    record
+}
+
+/// Reads rows of CSV with a header row, trimming whitespace around every field.
+fn csv_rows(reader: impl Read) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Row>>> {
+    let newlines = Rc::new(RefCell::new(VecDeque::new()));
+    let mut lines = Lines {
+        newlines: newlines.clone(),
+        passed: 0,
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .buffer_capacity(BUFFER_CAPACITY)
+        .flexible(true)
+        .from_reader(NewlineTracker {
+            inner: reader,
+            offset: 0,
+            newlines,
+        });
+
+    let mut headers = reader.byte_headers()?.clone();
+    headers.trim();
+
+    Ok(std::iter::from_fn(move || {
+        let mut record = ByteRecord::new();
+        match reader.read_byte_record(&mut record) {
+            Ok(false) => None,
+            Ok(true) => {
+                let line = lines.line_ending_at(reader.position().byte(), &record);
+                Some(Ok(csv_row(&headers, line, record)))
+            }
+            Err(error) => Some(Err(error.into())),
+        }
+    }))
+}
+
+/// Records the offset of every newline read, so line numbers can be recovered from the byte
+/// offsets of records. The csv reader's own line count skips blank lines and `\r\n`.
+struct NewlineTracker<R> {
+    inner: R,
+    offset: u64,
+    newlines: Rc<RefCell<VecDeque<u64>>>,
+}
+
+impl<R: Read> Read for NewlineTracker<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let offset = self.offset;
+        self.newlines.borrow_mut().extend(
+            (offset..)
+                .zip(&buf[..read])
+                .filter_map(|(offset, byte)| (*byte == b'\n').then_some(offset)),
+        );
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Consumes the newlines up to each record, so only those still buffered are kept.
+struct Lines {
+    newlines: Rc<RefCell<VecDeque<u64>>>,
+    passed: u64,
+}
+
+impl Lines {
+    /// The line a record starts on, given the reader's position just after it.
+    ///
+    /// The reader has consumed the first byte of the record's terminator, so everything before
+    /// that byte is the record itself and the lines before it.
+    fn line_ending_at(&mut self, end: u64, record: &ByteRecord) -> u64 {
+        let mut newlines = self.newlines.borrow_mut();
+        while newlines.front().is_some_and(|newline| *newline + 1 < end) {
+            newlines.pop_front();
+            self.passed += 1;
+        }
+        let within = record
+            .as_slice()
+            .iter()
+            .filter(|byte| **byte == b'\n')
+            .count();
+        1 + self.passed - within as u64
+    }
+}
+
+fn csv_row(headers: &ByteRecord, line: u64, raw: ByteRecord) -> Row {
+    let record = raw
+        .iter()
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let transaction = if raw.len() == headers.len() {
+        let mut trimmed = raw;
+        trimmed.trim();
+        trimmed
+            .deserialize(Some(headers))
+            .map_err(|error| csv_error(headers, &trimmed, error))
+    } else {
+        Err(InputError::WrongFieldCount {
+            expected: headers.len(),
+            found: raw.len(),
+        })
+    };
+
+    Row {
+        line,
+        record,
+        transaction,
+    }
+}
+
+fn csv_error(headers: &ByteRecord, record: &ByteRecord, error: csv::Error) -> InputError {
+    let csv::ErrorKind::Deserialize { err, .. } = error.into_kind() else {
+        return InputError::InvalidRecord("not a record".to_string());
+    };
+    field_error(headers, record, &err)
+}
+
+fn field_error(headers: &ByteRecord, record: &ByteRecord, error: &DeserializeError) -> InputError {
+    let message = error.kind().to_string();
+    let field = error
+        .field()
+        .map(|field| field as usize)
+        .or_else(|| failing_field(headers, record));
+    match field.and_then(|field| headers.get(field)) {
+        Some(field) => InputError::InvalidField {
+            field: String::from_utf8_lossy(field).into_owned(),
+            message,
+        },
+        None => InputError::InvalidRecord(message),
+    }
+}
+
+/// Finds the field at fault for errors raised by our own types, which csv cannot attribute.
+fn failing_field(headers: &ByteRecord, record: &ByteRecord) -> Option<usize> {
+    headers.iter().zip(record).position(|(header, field)| {
+        let field = ByteRecord::from(vec![field]);
+        match header {
+            b"type" => field.deserialize::<TransactionType>(None).is_err(),
+            b"amount" => field.deserialize::<Option<PositiveAmount>>(None).is_err(),
+            b"timestamp" => field.deserialize::<Option<Timestamp>>(None).is_err(),
+            _ => false,
+        }
+    })
+}
+
+/// Reads one JSON object per line, skipping blank lines.
+fn ndjson_rows(reader: impl Read) -> impl Iterator<Item = anyhow::Result<Row>> {
+    BufReader::new(reader)
+        .lines()
+        .zip(1..)
+        .filter(|(record, _)| !record.as_ref().is_ok_and(|record| record.trim().is_empty()))
+        .map(|(record, line)| {
+            let record = record?;
+            let transaction = serde_json::from_str(&record)
+                .map_err(|error| InputError::InvalidRecord(error.to_string()))
+                .and_then(json_transaction);
+            Ok(Row {
+                line,
+                record,
+                transaction,
+            })
+        })
+}
+
+/// Reads a JSON array of objects, which is parsed in full before any row is returned.
+fn json_rows(reader: impl Read) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Row>>> {
+    let values: Vec<Value> = serde_json::from_reader(BufReader::new(reader))?;
+
+    Ok(values.into_iter().zip(1..).map(|(value, line)| {
+        Ok(Row {
+            line,
+            record: value.to_string(),
+            transaction: json_transaction(value),
+        })
+    }))
+}
+
+pub(crate) fn json_transaction(value: Value) -> Result<Transaction, InputError> {
+    Transaction::deserialize(&value).map_err(|error| {
+        let message = error.to_string();
+        match failing_json_field(&value) {
+            Some(field) => InputError::InvalidField { field, message },
+            None => InputError::InvalidRecord(message),
+        }
+    })
+}
+
+/// Finds the field at fault, as serde_json does not say which.
+fn failing_json_field(value: &Value) -> Option<String> {
+    let (field, _) = value
+        .as_object()?
+        .iter()
+        .find(|(key, field)| match key.as_str() {
+            "type" => TransactionType::deserialize(*field).is_err(),
+            "client" => ClientID::deserialize(*field).is_err(),
+            "tx" => TransactionID::deserialize(*field).is_err(),
+            "amount" => Option::<PositiveAmount>::deserialize(*field).is_err(),
+            "timestamp" => Option::<Timestamp>::deserialize(*field).is_err(),
+            _ => false,
+        })?;
+    Some(field.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{InputError, InputFormat, LineParser, csv_rows};
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn rows_keep_their_line_and_raw_record() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit,2,2,2.0\n";
+        let rows: Vec<_> = csv_rows(input.as_bytes())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(2, rows.len());
+        assert_eq!(
+            (2, "deposit, 1, 1, 1.0"),
+            (rows[0].line, rows[0].record.as_str())
+        );
+        assert_eq!(Ok(Transaction::deposit(1, 1, 1.0)), rows[0].transaction);
+        assert_eq!(
+            (3, "deposit,2,2,2.0"),
+            (rows[1].line, rows[1].record.as_str())
+        );
+        assert_eq!(Ok(Transaction::deposit(2, 2, 2.0)), rows[1].transaction);
+    }
+
+    #[test]
+    fn lines_count_blank_lines_and_carriage_returns() {
+        let input = "type,client,tx,amount\r\n\r\nlemon\r\n\n\"le\nmon\"\nlemon";
+        let lines: Vec<_> = csv_rows(input.as_bytes())
+            .unwrap()
+            .map(|row| row.unwrap().line)
+            .collect();
+
+        assert_eq!(vec![3, 5, 7], lines);
+    }
+
+    #[test]
+    fn wrong_field_count() {
+        let input = "type,client,tx,amount\nlemon\n";
+        let row = csv_rows(input.as_bytes()).unwrap().next().unwrap().unwrap();
+
+        assert_eq!(
+            Err(InputError::WrongFieldCount {
+                expected: 4,
+                found: 1
+            }),
+            row.transaction
+        );
+    }
+
+    #[test]
+    fn invalid_field_is_named() {
+        let input = "type,client,tx,amount\ncheese,1,1,1.0\ndeposit,-1,1,1.0\ndeposit,1,-1,1.0\ndeposit,1,1,-1.0\n";
+        let fields: Vec<_> = csv_rows(input.as_bytes())
+            .unwrap()
+            .map(|row| match row.unwrap().transaction {
+                Err(InputError::InvalidField { field, .. }) => field,
+                _ => panic!("expected an invalid field"),
+            })
+            .collect();
+
+        assert_eq!(vec!["type", "client", "tx", "amount"], fields);
+    }
+
+    #[test]
+    fn invalid_timestamp_is_named() {
+        let input = "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,yesterday\n";
+        let row = csv_rows(input.as_bytes()).unwrap().next().unwrap().unwrap();
+
+        assert!(matches!(
+            row.transaction,
+            Err(InputError::InvalidField { field, .. }) if field == "timestamp"
+        ));
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(Some(InputFormat::Csv), InputFormat::from_path("a.csv"));
+        assert_eq!(
+            Some(InputFormat::Ndjson),
+            InputFormat::from_path("a.ndjson")
+        );
+        assert_eq!(Some(InputFormat::Ndjson), InputFormat::from_path("a.jsonl"));
+        assert_eq!(Some(InputFormat::Json), InputFormat::from_path("a.json"));
+        assert_eq!(None, InputFormat::from_path("-"));
+    }
+
+    #[test]
+    fn ndjson_rows() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}
+
+{"type": "withdrawal", "client": 2, "tx": 2, "amount": 1.2345}
+{"type": "dispute", "client": 3, "tx": 3}
+{"type": "resolve", "client": 4, "tx": 4, "amount": null}
+"#;
+        let rows: Vec<_> = InputFormat::Ndjson
+            .rows(input.as_bytes())
+            .unwrap()
+            .map(|row| row.unwrap())
+            .map(|row| (row.line, row.transaction))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (1, Ok(Transaction::deposit(1, 1, 1.0))),
+                (3, Ok(Transaction::withdrawal(2, 2, 1.2345))),
+                (4, Ok(Transaction::dispute(3, 3))),
+                (5, Ok(Transaction::resolve(4, 4))),
+            ],
+            rows
+        );
+    }
+
+    #[test]
+    fn json_rows() {
+        let input = r#"[
+            {"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"},
+            {"type": "chargeback", "client": 1, "tx": 1}
+        ]"#;
+        let rows: Vec<_> = InputFormat::Json
+            .rows(input.as_bytes())
+            .unwrap()
+            .map(|row| row.unwrap())
+            .map(|row| (row.line, row.record, row.transaction))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (
+                    1,
+                    r#"{"amount":"1.0","client":1,"tx":1,"type":"deposit"}"#.to_string(),
+                    Ok(Transaction::deposit(1, 1, 1.0))
+                ),
+                (
+                    2,
+                    r#"{"client":1,"tx":1,"type":"chargeback"}"#.to_string(),
+                    Ok(Transaction::chargeback(1, 1))
+                ),
+            ],
+            rows
+        );
+    }
+
+    #[test]
+    fn invalid_json_field_is_named() {
+        let input = r#"{"type": "cheese", "client": 1, "tx": 1}
+{"type": "deposit", "client": -1, "tx": 1}
+{"type": "deposit", "client": 1, "tx": "x"}
+{"type": "deposit", "client": 1, "tx": 1, "amount": -1}
+"#;
+        let fields: Vec<_> = InputFormat::Ndjson
+            .rows(input.as_bytes())
+            .unwrap()
+            .map(|row| match row.unwrap().transaction {
+                Err(InputError::InvalidField { field, .. }) => field,
+                _ => panic!("expected an invalid field"),
+            })
+            .collect();
+
+        assert_eq!(vec!["type", "client", "tx", "amount"], fields);
+    }
+
+    #[test]
+    fn valid_json_timestamp_is_not_blamed() {
+        let input = r#"{"type": "bogus", "client": 1, "tx": 1, "timestamp": "2024-01-01T00:00:00Z"}
+{"type": "deposit", "client": 1, "tx": 1, "amount": 1, "timestamp": "yesterday"}
+"#;
+        let fields: Vec<_> = InputFormat::Ndjson
+            .rows(input.as_bytes())
+            .unwrap()
+            .map(|row| match row.unwrap().transaction {
+                Err(InputError::InvalidField { field, .. }) => field,
+                _ => panic!("expected an invalid field"),
+            })
+            .collect();
+
+        assert_eq!(vec!["type", "timestamp"], fields);
+    }
+
+    #[test]
+    fn invalid_ndjson_line() {
+        let input = "{\"type\": \"deposit\"\nlemon\n";
+        let codes: Vec<_> = InputFormat::Ndjson
+            .rows(input.as_bytes())
+            .unwrap()
+            .map(|row| row.unwrap().transaction.unwrap_err().code())
+            .collect();
+
+        assert_eq!(vec!["invalid_record", "invalid_record"], codes);
+    }
+
+    #[test]
+    fn json_that_is_not_an_array_fails() {
+        assert!(InputFormat::Json.rows(&b"{}"[..]).is_err());
+    }
+
+    #[test]
+    fn line_parser_takes_headers_from_the_first_line() {
+        let mut parser = LineParser::new(InputFormat::Csv).unwrap();
+
+        assert!(parser.parse("type, client, tx, amount\r\n").is_none());
+        assert!(parser.parse("\n").is_none());
+        let row = parser.parse("deposit, 1, 1, 1.0\r\n").unwrap();
+        assert_eq!((3, "deposit, 1, 1, 1.0"), (row.line, row.record.as_str()));
+        assert_eq!(Ok(Transaction::deposit(1, 1, 1.0)), row.transaction);
+        let row = parser.parse("lemon").unwrap();
+        assert_eq!("wrong_field_count", row.transaction.unwrap_err().code());
+    }
+
+    #[test]
+    fn line_parser_with_headers() {
+        let mut parser = LineParser::new(InputFormat::Csv)
+            .unwrap()
+            .with_headers("type,client,tx,amount");
+
+        let row = parser.parse("withdrawal,2,7,0.5").unwrap();
+        assert_eq!(Ok(Transaction::withdrawal(2, 7, 0.5)), row.transaction);
+    }
+
+    #[test]
+    fn line_parser_reads_ndjson_but_not_json() {
+        let mut parser = LineParser::new(InputFormat::Ndjson).unwrap();
+        let row = parser
+            .parse(r#"{"type": "dispute", "client": 1, "tx": 2}"#)
+            .unwrap();
+
+        assert_eq!(Ok(Transaction::dispute(1, 2)), row.transaction);
+        assert!(LineParser::new(InputFormat::Json).is_err());
+    }
+}
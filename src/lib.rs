@@ -0,0 +1,57 @@
+//! A toy payments engine that applies deposits, withdrawals and disputes to client accounts.
+//!
+//! The [`Engine`] can be driven one [`Transaction`] at a time, or a whole CSV stream can be
+//! processed with [`run`].
+
+pub mod account;
+pub mod config;
+pub mod engine;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod input;
+pub mod output;
+pub mod rejection;
+pub mod reorder;
+pub mod report;
+pub mod run;
+pub mod server;
+pub mod shard;
+pub mod snapshot;
+pub mod spill;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod state;
+pub mod store;
+#[cfg(feature = "tokio")]
+pub mod stream;
+pub mod transaction;
+pub mod wal;
+
+pub use account::Account;
+pub use config::{
+    Config, DisputeWindow, ExcessPrecisionPolicy, LockedAccountPolicy, Precision, RedisputePolicy,
+    TimestampOrder,
+};
+pub use engine::Engine;
+pub use input::{InputFormat, LineParser};
+pub use output::{AccountOutput, OutputFormat, OutputOrder};
+pub use rejection::Rejection;
+pub use reorder::ReorderBuffer;
+pub use report::RejectionReport;
+pub use run::{Runner, run};
+pub use server::Server;
+pub use shard::{ShardedEngine, Shards};
+pub use snapshot::{SNAPSHOT_VERSION, Snapshot};
+pub use spill::SpillStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+pub use state::{StoredTransaction, TransactionState};
+pub use store::{MemoryStore, Store};
+#[cfg(feature = "tokio")]
+pub use stream::{AccountWatch, AsyncEngine, EngineHandle, StreamSummary, Verdict};
+pub use transaction::{
+    ClientID, PositiveAmount, Timestamp, Transaction, TransactionID, TransactionType,
+};
+pub use wal::WriteAheadLog;
@@ -1,16 +1,448 @@
-mod account;
-mod engine;
-mod output;
-mod run;
-mod transaction;
-
-use crate::run::run;
 use anyhow::Context;
+use clap::{Parser, Subcommand};
+use rust_decimal::RoundingStrategy;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use toy_engine::{
+    Config, DisputeWindow, Engine, ExcessPrecisionPolicy, InputFormat, OutputFormat, OutputOrder,
+    Precision, RejectionReport, Runner, Server, ShardedEngine, Snapshot, SpillStore, Store,
+    TimestampOrder, WriteAheadLog,
+};
+
+/// How many transactions to log between each sync to disk, unless `--wal-sync-every` is given.
+const DEFAULT_WAL_SYNC_EVERY: usize = 1000;
+
+/// Applies a stream of transactions to client accounts and writes the resulting accounts to
+/// stdout.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Files of transactions, processed in order as one stream. `-` reads stdin, and can be
+    /// given once.
+    paths: Vec<String>,
+    /// The format of every input: csv, ndjson or json. Otherwise guessed from each file's
+    /// extension, falling back to csv.
+    #[arg(long)]
+    input_format: Option<InputFormat>,
+    /// The format to write accounts in: csv, json, ndjson or table. Defaults to csv.
+    #[arg(long)]
+    output_format: Option<OutputFormat>,
+    /// The order to write accounts in: client, total or locked-first. Ties are broken by client.
+    #[arg(long, default_value = "client")]
+    order: OutputOrder,
+    /// How many decimal places amounts are accepted and written with. Defaults to 4.
+    #[arg(long, value_name = "PLACES", value_parser = clap::value_parser!(u32).range(0..=28))]
+    decimal_places: Option<u32>,
+    /// Round amounts with more decimal places than allowed, rather than rejecting them:
+    /// half-even, half-up, half-down, down or up.
+    #[arg(long, value_name = "STRATEGY", value_parser = parse_rounding)]
+    round: Option<RoundingStrategy>,
+    /// Write every rejected row, and why, as CSV to this path.
+    #[arg(long)]
+    rejections: Option<PathBuf>,
+    /// Fail on the first malformed row, without writing any accounts.
+    #[arg(long)]
+    strict: bool,
+    /// Start from the engine state saved in this snapshot, rather than from no accounts.
+    #[arg(long)]
+    state_in: Option<PathBuf>,
+    /// Save the engine state to this snapshot once every input has been processed.
+    #[arg(long)]
+    state_out: Option<PathBuf>,
+    /// Log every transaction to this path before applying it, first replaying whatever the log
+    /// already holds on top of `--state-in`. Saving `--state-out` empties the log.
+    #[arg(long)]
+    wal: Option<PathBuf>,
+    /// How many transactions to log between each sync to disk. Defaults to 1000.
+    #[arg(long, value_name = "COUNT")]
+    wal_sync_every: Option<usize>,
+    /// Hold roughly at most this much transaction history in memory, e.g. `512M`, spilling the
+    /// rest to disk.
+    #[arg(long, value_parser = parse_size)]
+    transaction_memory: Option<usize>,
+    /// Where to spill transaction history beyond `--transaction-memory`. Defaults to the
+    /// system's temporary directory.
+    #[arg(long, requires = "transaction_memory")]
+    spill_dir: Option<PathBuf>,
+    /// Reject disputes on a transaction once this many later deposits and withdrawals have been
+    /// applied to its account, and forget it soon after.
+    #[arg(long, value_name = "COUNT")]
+    dispute_window_transactions: Option<u64>,
+    /// Reject disputes on a timestamped transaction once this long has passed since, e.g. `90d`,
+    /// and forget it soon after. Measured against the latest `timestamp` column applied.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    dispute_window: Option<u64>,
+    /// Reject a transaction timestamped earlier than one already applied.
+    #[arg(long)]
+    monotonic_timestamps: bool,
+    /// Hold transactions back until any timestamped up to this much earlier have arrived, e.g.
+    /// `5s`, and apply them in timestamp order. Implies `--monotonic-timestamps`, so a
+    /// transaction later than the window is rejected.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    reorder_window: Option<u64>,
+    /// Apply transactions on this many threads, each holding a share of the clients, while
+    /// reading continues on the main thread.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "rejections",
+            "wal",
+            "transaction_memory",
+            "reorder_window",
+            "monotonic_timestamps",
+            "dispute_window",
+        ]
+    )]
+    threads: Option<usize>,
+    /// Keep accounts and transactions in this SQLite database, carrying on from whatever it
+    /// already holds, rather than in memory.
+    #[cfg(feature = "sqlite")]
+    #[arg(long, conflicts_with_all = ["wal", "transaction_memory", "threads"])]
+    sqlite: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Apply transactions sent over TCP to one engine until stopped.
+    ///
+    /// Each connection sends `type,client,tx,amount` lines, without a header row, and each is
+    /// answered with `ok` or why it was rejected. `SNAPSHOT` is answered with every account as
+    /// CSV, then a blank line.
+    Serve {
+        /// The address to listen on, e.g. `127.0.0.1:7878`.
+        #[arg(long)]
+        listen: SocketAddr,
+    },
+    /// Serve a JSON API over HTTP for submitting transactions and looking up accounts and
+    /// transactions, applied to one engine until stopped.
+    #[cfg(feature = "http")]
+    ServeHttp {
+        /// The address to listen on, e.g. `127.0.0.1:8080`.
+        #[arg(long)]
+        listen: SocketAddr,
+    },
+    /// Serve the gRPC service in `proto/engine.proto`, applying transactions to one engine until
+    /// stopped.
+    #[cfg(feature = "grpc")]
+    ServeGrpc {
+        /// The address to listen on, e.g. `127.0.0.1:50051`.
+        #[arg(long)]
+        listen: SocketAddr,
+    },
+}
 
 fn main() -> anyhow::Result<()> {
-    let path = std::env::args().nth(1).context("missing argument")?;
+    let args = Args::parse();
+    let config = Config {
+        dispute_window: DisputeWindow {
+            transactions: args.dispute_window_transactions,
+            millis: args.dispute_window,
+        },
+        timestamp_order: if args.monotonic_timestamps || args.reorder_window.is_some() {
+            TimestampOrder::Monotonic
+        } else {
+            TimestampOrder::Unchecked
+        },
+        output_order: args.order,
+        precision: Precision {
+            decimal_places: args
+                .decimal_places
+                .unwrap_or(Precision::default().decimal_places),
+            excess_precision_policy: args
+                .round
+                .map_or(ExcessPrecisionPolicy::Reject, ExcessPrecisionPolicy::Round),
+        },
+        ..Config::default()
+    };
+
+    match args.command {
+        Some(Command::Serve { listen }) => {
+            ensure_servable(&args, "serve")?;
+            return serve(&args, config, listen);
+        }
+        #[cfg(feature = "http")]
+        Some(Command::ServeHttp { listen }) => {
+            ensure_servable(&args, "serve-http")?;
+            return serve_http(&args, config, listen);
+        }
+        #[cfg(feature = "grpc")]
+        Some(Command::ServeGrpc { listen }) => {
+            ensure_servable(&args, "serve-grpc")?;
+            return serve_grpc(&args, config, listen);
+        }
+        None => (),
+    }
+
+    anyhow::ensure!(!args.paths.is_empty(), "missing argument");
+    anyhow::ensure!(
+        args.paths.iter().filter(|path| *path == "-").count() <= 1,
+        "stdin (`-`) can only be read once"
+    );
+    let inputs = args
+        .paths
+        .iter()
+        .map(|path| open(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if let Some(threads) = args.threads {
+        return process_sharded(&args, inputs, config, threads);
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.sqlite {
+        let store = toy_engine::SqliteStore::open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        return process(&args, inputs, Engine::with_store(config, store));
+    }
+
+    if let Some(bytes) = args.transaction_memory {
+        let directory = args.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+        let store = SpillStore::with_memory_limit(&directory, bytes)
+            .with_context(|| format!("failed to spill to {}", directory.display()))?;
+        return process(&args, inputs, Engine::with_store(config, store));
+    }
+
+    process(&args, inputs, Engine::new(config))
+}
+
+fn process<S: Store>(
+    args: &Args,
+    inputs: Vec<Box<dyn Read>>,
+    mut engine: Engine<S>,
+) -> anyhow::Result<()> {
+    let log_sequence = match &args.state_in {
+        Some(path) => load(path, &mut engine)?,
+        None => 0,
+    };
+    let log = match &args.wal {
+        Some(path) => {
+            let sync_every = args.wal_sync_every.unwrap_or(DEFAULT_WAL_SYNC_EVERY);
+            Some(
+                WriteAheadLog::recover(path, &mut engine, log_sequence, sync_every)
+                    .with_context(|| format!("failed to recover {}", path.display()))?,
+            )
+        }
+        None => None,
+    };
+
+    let mut runner = Runner::new(engine).with_strict(args.strict);
+    if let Some(window) = args.reorder_window {
+        runner = runner.with_reorder_window(window);
+    }
+    if let Some(log) = log {
+        runner = runner.with_log(log);
+    }
+    if let Some(path) = &args.rejections {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        runner = runner.with_report(RejectionReport::new(std::io::BufWriter::new(file)));
+    }
+
+    for (path, input) in args.paths.iter().zip(inputs) {
+        runner.process(path, input_format(args, path), input)?;
+    }
+
+    if let Some(path) = &args.state_out {
+        save(path, &runner.snapshot()?)?;
+        runner.checkpoint()?;
+    }
+
+    runner.finish(
+        args.output_format.unwrap_or_default(),
+        std::io::stdout().lock(),
+    )
+}
+
+fn process_sharded(
+    args: &Args,
+    inputs: Vec<Box<dyn Read>>,
+    config: Config,
+    threads: usize,
+) -> anyhow::Result<()> {
+    let snapshot = match &args.state_in {
+        Some(path) => read_snapshot(path)?,
+        None => Snapshot::default(),
+    };
+    let mut engine = ShardedEngine::restore(config, threads, snapshot)?.with_strict(args.strict);
+
+    for (path, input) in args.paths.iter().zip(inputs) {
+        engine.process(path, input_format(args, path), input)?;
+    }
+    let shards = engine.join()?;
+
+    if let Some(path) = &args.state_out {
+        save(path, &shards.snapshot())?;
+    }
+    shards.write(
+        args.output_format.unwrap_or_default(),
+        std::io::stdout().lock(),
+    )
+}
+
+/// Fails if `args` hold any paths or options that only apply to processing files, which a
+/// server would otherwise silently ignore.
+fn ensure_servable(args: &Args, command: &str) -> anyhow::Result<()> {
+    let file_only = [
+        ("paths", !args.paths.is_empty()),
+        ("--input-format", args.input_format.is_some()),
+        ("--output-format", args.output_format.is_some()),
+        ("--rejections", args.rejections.is_some()),
+        ("--strict", args.strict),
+        ("--state-out", args.state_out.is_some()),
+        ("--wal", args.wal.is_some()),
+        ("--wal-sync-every", args.wal_sync_every.is_some()),
+        ("--transaction-memory", args.transaction_memory.is_some()),
+        ("--reorder-window", args.reorder_window.is_some()),
+        ("--threads", args.threads.is_some()),
+        #[cfg(feature = "sqlite")]
+        ("--sqlite", args.sqlite.is_some()),
+    ];
+    match file_only.into_iter().find(|&(_, given)| given) {
+        Some((option, _)) => anyhow::bail!("{option} cannot be used with {command}"),
+        None => Ok(()),
+    }
+}
+
+fn serve(args: &Args, config: Config, listen: SocketAddr) -> anyhow::Result<()> {
+    let mut engine = Engine::new(config);
+    if let Some(path) = &args.state_in {
+        load(path, &mut engine)?;
+    }
+    let server =
+        Server::bind(listen, engine).with_context(|| format!("failed to listen on {listen}"))?;
+    eprintln!("listening on {}", server.local_addr()?);
+    server.run()
+}
+
+#[cfg(feature = "http")]
+fn serve_http(args: &Args, config: Config, listen: SocketAddr) -> anyhow::Result<()> {
+    let mut engine = Engine::new(config);
+    if let Some(path) = &args.state_in {
+        load(path, &mut engine)?;
+    }
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("failed to listen on {listen}"))?;
+        eprintln!("listening on {}", listener.local_addr()?);
+        let engine = toy_engine::AsyncEngine::spawn(engine, toy_engine::stream::DEFAULT_CAPACITY);
+        toy_engine::http::serve(listener, engine.handle()).await?;
+        Ok(())
+    })
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(args: &Args, config: Config, listen: SocketAddr) -> anyhow::Result<()> {
+    let mut engine = Engine::new(config);
+    if let Some(path) = &args.state_in {
+        load(path, &mut engine)?;
+    }
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("failed to listen on {listen}"))?;
+        eprintln!("listening on {}", listener.local_addr()?);
+        let engine = toy_engine::AsyncEngine::spawn(engine, toy_engine::stream::DEFAULT_CAPACITY);
+        toy_engine::grpc::serve(listener, engine.handle()).await?;
+        Ok(())
+    })
+}
+
+fn input_format(args: &Args, path: &str) -> InputFormat {
+    args.input_format
+        .or_else(|| InputFormat::from_path(path))
+        .unwrap_or_default()
+}
 
-    let file = std::fs::File::open(&path)?;
+/// Parses a number of bytes with an optional `K`, `M` or `G` suffix, each 1024 times the last.
+fn parse_size(size: &str) -> anyhow::Result<usize> {
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&size[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&size[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&size[..index], 1 << 30),
+        _ => (size, 1),
+    };
+    let digits: usize = digits
+        .parse()
+        .map_err(|_| anyhow::anyhow!("expected a size such as 4096, 64K, 512M or 2G"))?;
+    digits
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("size is too large"))
+}
+
+/// Parses the name of a rounding strategy for `--round`, where `half-up` rounds halves away from
+/// zero.
+fn parse_rounding(strategy: &str) -> anyhow::Result<RoundingStrategy> {
+    match strategy {
+        "half-even" => Ok(RoundingStrategy::MidpointNearestEven),
+        "half-up" => Ok(RoundingStrategy::MidpointAwayFromZero),
+        "half-down" => Ok(RoundingStrategy::MidpointTowardZero),
+        "down" => Ok(RoundingStrategy::ToZero),
+        "up" => Ok(RoundingStrategy::AwayFromZero),
+        _ => anyhow::bail!("expected one of half-even, half-up, half-down, down or up"),
+    }
+}
+
+/// Parses a number of milliseconds with a `ms`, `s`, `m`, `h` or `d` suffix.
+fn parse_duration(duration: &str) -> anyhow::Result<u64> {
+    let error = || anyhow::anyhow!("expected a duration such as 500ms, 30s, 15m, 12h or 90d");
+    let split = duration
+        .find(|character: char| !character.is_ascii_digit())
+        .ok_or_else(error)?;
+    let (digits, unit) = duration.split_at(split);
+    let multiplier = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(error()),
+    };
+    let digits: u64 = digits.parse().map_err(|_| error())?;
+    digits
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("duration is too long"))
+}
+
+/// Opens a path for reading, where `-` means stdin.
+fn open(path: &str) -> anyhow::Result<Box<dyn Read>> {
+    if path == "-" {
+        return Ok(Box::new(std::io::stdin().lock()));
+    }
+    let file = std::fs::File::open(path).with_context(|| format!("failed to open {path}"))?;
+    Ok(Box::new(file))
+}
+
+/// Adds the engine state saved at `path` to `engine`, returning the last log record it
+/// includes.
+fn load<S: Store>(path: &Path, engine: &mut Engine<S>) -> anyhow::Result<u64> {
+    let snapshot = read_snapshot(path)?;
+    let log_sequence = snapshot.log_sequence;
+    engine
+        .load(snapshot)
+        .with_context(|| format!("failed to load state from {}", path.display()))?;
+    Ok(log_sequence)
+}
+
+fn read_snapshot(path: &Path) -> anyhow::Result<Snapshot> {
+    let context = || format!("failed to load state from {}", path.display());
+    let file = std::fs::File::open(path).with_context(context)?;
+    Snapshot::read(std::io::BufReader::new(file)).with_context(context)
+}
 
-    run(file, std::io::stdout().lock())
+/// Writes the snapshot beside `path` before moving it into place, so a failed run never leaves
+/// a partial snapshot behind.
+fn save(path: &Path, snapshot: &Snapshot) -> anyhow::Result<()> {
+    let context = || format!("failed to save state to {}", path.display());
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let file = std::fs::File::create(&partial).with_context(context)?;
+    snapshot
+        .write(std::io::BufWriter::new(&file))
+        .with_context(context)?;
+    file.sync_all().with_context(context)?;
+    std::fs::rename(&partial, path).with_context(context)
 }
@@ -2,8 +2,11 @@ use crate::account::Account;
 use crate::transaction::ClientID;
 use rust_decimal::Decimal;
 use serde::Serialize;
+use std::io::Write;
+use std::str::FromStr;
 
-#[derive(Debug, Serialize)]
+/// The externally visible state of an account, as written to the output.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct AccountOutput {
     pub client: ClientID,
     pub available: Decimal,
@@ -14,13 +17,228 @@ pub struct AccountOutput {
 
 impl From<(&ClientID, &Account)> for AccountOutput {
     fn from((client, account): (&ClientID, &Account)) -> Self {
-        let held: Decimal = account.held_transactions.values().sum();
         Self {
             client: *client,
             available: account.available,
-            held,
-            total: account.available + held,
+            held: account.held,
+            total: account.available + account.held,
             locked: account.locked,
         }
     }
 }
+
+impl AccountOutput {
+    /// Pads or rounds the balances to exactly `decimal_places`.
+    pub fn rescaled(mut self, decimal_places: u32) -> Self {
+        for balance in [&mut self.available, &mut self.held, &mut self.total] {
+            balance.rescale(decimal_places);
+        }
+        self
+    }
+}
+
+/// The order accounts are output in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputOrder {
+    /// Ascending client id.
+    #[default]
+    Client,
+    /// Ascending total, then by client id.
+    Total,
+    /// Locked accounts before unlocked ones, then by client id.
+    LockedFirst,
+}
+
+impl OutputOrder {
+    /// Sorts rows that are already in client order.
+    pub fn sort(self, rows: &mut [AccountOutput]) {
+        match self {
+            OutputOrder::Client => (),
+            OutputOrder::Total => rows.sort_by_key(|row| row.total),
+            OutputOrder::LockedFirst => rows.sort_by_key(|row| !row.locked),
+        }
+    }
+}
+
+impl FromStr for OutputOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(order: &str) -> anyhow::Result<Self> {
+        match order {
+            "client" => Ok(OutputOrder::Client),
+            "total" => Ok(OutputOrder::Total),
+            "locked-first" => Ok(OutputOrder::LockedFirst),
+            _ => anyhow::bail!("expected one of client, total or locked-first"),
+        }
+    }
+}
+
+/// How accounts are written out.
+///
+/// Balances are always written as strings, so no precision is lost to floating point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Comma separated values with a header row.
+    #[default]
+    Csv,
+    /// A single JSON array of objects.
+    Json,
+    /// One JSON object per line.
+    Ndjson,
+    /// An aligned table for reading in a terminal.
+    Table,
+}
+
+impl OutputFormat {
+    pub fn write(
+        self,
+        rows: impl Iterator<Item = AccountOutput>,
+        mut writer: impl Write,
+    ) -> anyhow::Result<()> {
+        match self {
+            OutputFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(&mut writer);
+                for row in rows {
+                    writer.serialize(row)?;
+                }
+                writer.flush()?;
+            }
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut writer, &rows.collect::<Vec<_>>())?;
+                writeln!(writer)?;
+            }
+            OutputFormat::Ndjson => {
+                for row in rows {
+                    serde_json::to_writer(&mut writer, &row)?;
+                    writeln!(writer)?;
+                }
+            }
+            OutputFormat::Table => write_table(rows, &mut writer)?,
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> anyhow::Result<Self> {
+        match format {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "table" => Ok(OutputFormat::Table),
+            _ => anyhow::bail!("expected one of csv, json, ndjson or table"),
+        }
+    }
+}
+
+/// Writes every column right aligned to its widest cell.
+fn write_table(
+    rows: impl Iterator<Item = AccountOutput>,
+    writer: &mut impl Write,
+) -> std::io::Result<()> {
+    let header = ["client", "available", "held", "total", "locked"].map(String::from);
+    let cells: Vec<[String; 5]> = std::iter::once(header)
+        .chain(rows.map(|row| {
+            [
+                row.client.to_string(),
+                row.available.to_string(),
+                row.held.to_string(),
+                row.total.to_string(),
+                row.locked.to_string(),
+            ]
+        }))
+        .collect();
+
+    let mut widths = [0; 5];
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in &cells {
+        let line: Vec<_> = widths
+            .iter()
+            .zip(row)
+            .map(|(width, cell)| format!("{cell:>width$}"))
+            .collect();
+        writeln!(writer, "{}", line.join("  "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::{AccountOutput, OutputFormat};
+    use rust_decimal::Decimal;
+
+    fn rows() -> impl Iterator<Item = AccountOutput> {
+        [
+            AccountOutput {
+                client: 1,
+                available: Decimal::new(15000, 4),
+                held: Decimal::new(0, 4),
+                total: Decimal::new(15000, 4),
+                locked: false,
+            },
+            AccountOutput {
+                client: 65535,
+                available: Decimal::new(-1234567, 4),
+                held: Decimal::new(20000, 4),
+                total: Decimal::new(-1214567, 4),
+                locked: true,
+            },
+        ]
+        .into_iter()
+    }
+
+    fn write(format: OutputFormat) -> String {
+        let mut output = Vec::new();
+        format.write(rows(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(
+            "client,available,held,total,locked\n\
+1,1.5000,0.0000,1.5000,false\n\
+65535,-123.4567,2.0000,-121.4567,true\n",
+            write(OutputFormat::Csv)
+        );
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            r#"[{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false},{"client":65535,"available":"-123.4567","held":"2.0000","total":"-121.4567","locked":true}]
+"#,
+            write(OutputFormat::Json)
+        );
+    }
+
+    #[test]
+    fn ndjson() {
+        assert_eq!(
+            r#"{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}
+{"client":65535,"available":"-123.4567","held":"2.0000","total":"-121.4567","locked":true}
+"#,
+            write(OutputFormat::Ndjson)
+        );
+    }
+
+    #[test]
+    fn table() {
+        assert_eq!(
+            "\
+client  available    held      total  locked
+     1     1.5000  0.0000     1.5000   false
+ 65535  -123.4567  2.0000  -121.4567    true
+",
+            write(OutputFormat::Table)
+        );
+    }
+}
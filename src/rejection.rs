@@ -0,0 +1,56 @@
+use thiserror::Error;
+
+/// The reason a transaction had no effect.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    #[error("deposits and withdrawals require an amount, disputes must not have one")]
+    MalformedAmount,
+    #[error("amount has more decimal places than allowed")]
+    ExcessPrecision,
+    #[error("transaction id has already been used")]
+    DuplicateTransaction,
+    #[error("client has no account")]
+    UnknownClient,
+    #[error("account is locked")]
+    AccountLocked,
+    #[error("insufficient available funds")]
+    InsufficientFunds,
+    #[error("transaction does not exist")]
+    UnknownTransaction,
+    #[error("transaction belongs to a different client")]
+    ClientMismatch,
+    #[error("transaction is already disputed")]
+    AlreadyDisputed,
+    #[error("transaction is not disputed")]
+    NotDisputed,
+    #[error("transaction's dispute has already been settled")]
+    DisputeClosed,
+    #[error("transaction was rejected so cannot be disputed")]
+    NotApplied,
+    #[error("transaction is outside the dispute window")]
+    DisputeExpired,
+    #[error("timestamp is earlier than a transaction already applied")]
+    OutOfOrder,
+}
+
+impl Rejection {
+    /// A stable, machine-readable name for the reason.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::MalformedAmount => "malformed_amount",
+            Rejection::ExcessPrecision => "excess_precision",
+            Rejection::DuplicateTransaction => "duplicate_transaction",
+            Rejection::UnknownClient => "unknown_client",
+            Rejection::AccountLocked => "account_locked",
+            Rejection::InsufficientFunds => "insufficient_funds",
+            Rejection::UnknownTransaction => "unknown_transaction",
+            Rejection::ClientMismatch => "client_mismatch",
+            Rejection::AlreadyDisputed => "already_disputed",
+            Rejection::NotDisputed => "not_disputed",
+            Rejection::DisputeClosed => "dispute_closed",
+            Rejection::NotApplied => "not_applied",
+            Rejection::DisputeExpired => "dispute_expired",
+            Rejection::OutOfOrder => "out_of_order",
+        }
+    }
+}
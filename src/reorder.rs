@@ -0,0 +1,184 @@
+use crate::transaction::Timestamp;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+/// Holds items back until any that arrive up to `window` milliseconds late have been seen, then
+/// releases them in timestamp order.
+///
+/// Items with the same timestamp keep the order they arrived in. An item without a timestamp is
+/// treated as happening at the latest timestamp seen so far, so it stays behind everything
+/// that arrived before it, but is released as soon as nothing timestamped is held back.
+pub struct ReorderBuffer<T> {
+    window: u64,
+    pending: BinaryHeap<Reverse<Pending<T>>>,
+    latest: Option<Timestamp>,
+    arrivals: u64,
+    /// How many of the pending items arrived with a timestamp of their own.
+    timestamped: usize,
+}
+
+struct Pending<T> {
+    timestamp: Timestamp,
+    arrival: u64,
+    timestamped: bool,
+    item: T,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            pending: BinaryHeap::new(),
+            latest: None,
+            arrivals: 0,
+            timestamped: 0,
+        }
+    }
+
+    pub fn push(&mut self, timestamp: Option<Timestamp>, item: T) {
+        self.latest = self.latest.max(timestamp);
+        self.timestamped += usize::from(timestamp.is_some());
+        self.pending.push(Reverse(Pending {
+            timestamp: timestamp
+                .or(self.latest)
+                .unwrap_or(Timestamp::from_millis(0)),
+            arrival: self.arrivals,
+            timestamped: timestamp.is_some(),
+            item,
+        }));
+        self.arrivals += 1;
+    }
+
+    /// Releases the earliest item, if nothing arriving later could still come before it, or if
+    /// it and everything else held back arrived without a timestamp.
+    pub fn pop_ready(&mut self) -> Option<T> {
+        let Reverse(earliest) = self.pending.peek()?;
+        if self.timestamped > 0
+            && self
+                .latest
+                .map_or(0, Timestamp::millis)
+                .saturating_sub(earliest.timestamp.millis())
+                < self.window
+        {
+            return None;
+        }
+        self.pop()
+    }
+
+    /// Releases the earliest item regardless of the window, once no more are coming.
+    pub fn pop(&mut self) -> Option<T> {
+        let Reverse(pending) = self.pending.pop()?;
+        self.timestamped -= usize::from(pending.timestamped);
+        Some(pending.item)
+    }
+
+    /// The number of items held back.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl<T> PartialEq for Pending<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Pending<T> {}
+
+impl<T> PartialOrd for Pending<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Pending<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.arrival).cmp(&(other.timestamp, other.arrival))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reorder::ReorderBuffer;
+    use crate::transaction::Timestamp;
+
+    fn push(buffer: &mut ReorderBuffer<&'static str>, millis: Option<u64>, item: &'static str) {
+        buffer.push(millis.map(Timestamp::from_millis), item);
+    }
+
+    fn ready(buffer: &mut ReorderBuffer<&'static str>) -> Vec<&'static str> {
+        std::iter::from_fn(|| buffer.pop_ready()).collect()
+    }
+
+    #[test]
+    fn items_are_held_for_the_window() {
+        let mut buffer = ReorderBuffer::new(100);
+
+        push(&mut buffer, Some(1000), "a");
+        push(&mut buffer, Some(1050), "b");
+        assert!(ready(&mut buffer).is_empty());
+
+        push(&mut buffer, Some(1100), "c");
+        assert_eq!(vec!["a"], ready(&mut buffer));
+        assert_eq!(2, buffer.len());
+    }
+
+    #[test]
+    fn late_items_are_released_in_timestamp_order() {
+        let mut buffer = ReorderBuffer::new(100);
+
+        push(&mut buffer, Some(1050), "b");
+        push(&mut buffer, Some(1000), "a");
+        push(&mut buffer, Some(1075), "c");
+        push(&mut buffer, Some(1300), "d");
+
+        assert_eq!(vec!["a", "b", "c"], ready(&mut buffer));
+        assert_eq!(Some("d"), buffer.pop());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn equal_timestamps_keep_their_arrival_order() {
+        let mut buffer = ReorderBuffer::new(0);
+
+        push(&mut buffer, Some(5), "a");
+        push(&mut buffer, Some(5), "b");
+        push(&mut buffer, Some(5), "c");
+
+        assert_eq!(vec!["a", "b", "c"], ready(&mut buffer));
+    }
+
+    #[test]
+    fn untimestamped_items_follow_the_latest_seen() {
+        let mut buffer = ReorderBuffer::new(100);
+
+        push(&mut buffer, Some(1000), "a");
+        push(&mut buffer, None, "b");
+        push(&mut buffer, Some(990), "c");
+        push(&mut buffer, Some(2000), "d");
+
+        assert_eq!(vec!["c", "a", "b"], ready(&mut buffer));
+    }
+
+    #[test]
+    fn untimestamped_items_are_not_held_alone() {
+        let mut buffer = ReorderBuffer::new(100);
+
+        push(&mut buffer, None, "a");
+        push(&mut buffer, None, "b");
+        assert_eq!(vec!["a", "b"], ready(&mut buffer));
+
+        push(&mut buffer, Some(1000), "c");
+        push(&mut buffer, None, "d");
+        assert!(ready(&mut buffer).is_empty());
+
+        push(&mut buffer, Some(1100), "e");
+        assert_eq!(vec!["c", "d"], ready(&mut buffer));
+        assert_eq!(1, buffer.len());
+    }
+}
@@ -0,0 +1,50 @@
+use crate::transaction::Timestamp;
+use serde::Serialize;
+use std::io::Write;
+
+/// Writes every rejected row, and why, as CSV.
+pub struct RejectionReport<'a> {
+    writer: csv::Writer<Box<dyn Write + 'a>>,
+}
+
+#[derive(Serialize)]
+struct RejectedRow<'r> {
+    input: &'r str,
+    line: u64,
+    record: &'r str,
+    reason: &'static str,
+    timestamp: Option<String>,
+}
+
+impl<'a> RejectionReport<'a> {
+    pub fn new(writer: impl Write + 'a) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(Box::new(writer)),
+        }
+    }
+
+    /// Records that the row at `line` of `input` was rejected for `reason`, along with the
+    /// transaction's timestamp if it could be read.
+    pub fn reject(
+        &mut self,
+        input: &str,
+        line: u64,
+        record: &str,
+        reason: &'static str,
+        timestamp: Option<Timestamp>,
+    ) -> anyhow::Result<()> {
+        self.writer.serialize(RejectedRow {
+            input,
+            line,
+            record,
+            reason,
+            timestamp: timestamp.map(|timestamp| timestamp.to_string()),
+        })?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
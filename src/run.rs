@@ -1,43 +1,232 @@
 use crate::engine::Engine;
+use crate::input::InputFormat;
+use crate::output::OutputFormat;
+use crate::reorder::ReorderBuffer;
+use crate::report::RejectionReport;
+use crate::snapshot::Snapshot;
+use crate::store::{MemoryStore, Store};
 use crate::transaction::Transaction;
+use crate::wal::WriteAheadLog;
 use std::io::{Read, Write};
+use std::sync::Arc;
 
-const BUFFER_CAPACITY: usize = 64 * 1024;
+/// Reads transactions as CSV from each of `readers` in turn, applies them to a new [`Engine`]
+/// and writes the resulting accounts as CSV to `writer`.
+///
+/// Every reader starts with its own header row. Rows that fail to deserialize, and
+/// transactions the engine rejects, are skipped.
+pub fn run<R: Read>(
+    readers: impl IntoIterator<Item = R>,
+    writer: impl Write,
+) -> anyhow::Result<()> {
+    let mut runner = Runner::default();
 
-pub fn run(reader: impl Read, writer: impl Write) -> anyhow::Result<()> {
-    let mut reader = csv::ReaderBuilder::new()
-        .buffer_capacity(BUFFER_CAPACITY)
-        .trim(csv::Trim::All)
-        .from_reader(reader);
+    for reader in readers {
+        runner.process("", InputFormat::Csv, reader)?;
+    }
+
+    runner.finish(OutputFormat::Csv, writer)
+}
+
+/// Feeds any number of inputs into one [`Engine`], optionally reporting the rows it rejects.
+pub struct Runner<'a, S = MemoryStore> {
+    engine: Engine<S>,
+    report: Option<RejectionReport<'a>>,
+    log: Option<WriteAheadLog>,
+    strict: bool,
+    reorder: Option<ReorderBuffer<HeldRow>>,
+}
+
+/// A transaction held back for reordering, with where it came from.
+struct HeldRow {
+    /// Shared by every row held back from the same input.
+    input: Arc<str>,
+    line: u64,
+    record: String,
+    transaction: Transaction,
+}
+
+impl Default for Runner<'_> {
+    fn default() -> Self {
+        Self::new(Engine::default())
+    }
+}
+
+impl<'a, S: Store> Runner<'a, S> {
+    /// Creates a runner that applies transactions to `engine`.
+    pub fn new(engine: Engine<S>) -> Self {
+        Self {
+            engine,
+            report: None,
+            log: None,
+            strict: false,
+            reorder: None,
+        }
+    }
+
+    /// Writes every rejected row to `report`, whether it failed to deserialize or was
+    /// rejected by the engine.
+    pub fn with_report(mut self, report: RejectionReport<'a>) -> Self {
+        self.report = Some(report);
+        self
+    }
+
+    /// Appends every transaction to `log` before applying it. The engine should already hold
+    /// the log's state, as left by [`WriteAheadLog::recover`].
+    pub fn with_log(mut self, log: WriteAheadLog) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Fails on the first row that cannot be deserialized, or whose amount is missing,
+    /// unexpected or too precise, rather than skipping it.
+    ///
+    /// Transactions rejected by the engine for any other reason are still skipped.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Holds transactions back until any timestamped up to `window` milliseconds earlier have
+    /// arrived, then applies them in timestamp order, even across inputs.
+    ///
+    /// Anything still held back is applied on [`finish`](Self::finish) or
+    /// [`snapshot`](Self::snapshot). Transactions are logged as they are applied, so a crash
+    /// loses those held back just as it loses unread input.
+    pub fn with_reorder_window(mut self, window: u64) -> Self {
+        self.reorder = Some(ReorderBuffer::new(window));
+        self
+    }
+
+    /// The engine transactions have been applied to so far.
+    pub fn engine(&self) -> &Engine<S> {
+        &self.engine
+    }
 
-    let mut engine = Engine::default();
+    /// Captures the engine's state, along with how much of the log it includes, once every
+    /// transaction held back for reordering has been applied.
+    pub fn snapshot(&mut self) -> anyhow::Result<Snapshot> {
+        self.release_all()?;
+        Ok(Snapshot {
+            log_sequence: self.log.as_ref().map_or(0, WriteAheadLog::sequence),
+            ..self.engine.try_snapshot()?
+        })
+    }
+
+    /// Empties the log, if there is one, once a [`snapshot`](Self::snapshot) has been saved.
+    pub fn checkpoint(&mut self) -> anyhow::Result<()> {
+        match &mut self.log {
+            Some(log) => log.checkpoint(),
+            None => Ok(()),
+        }
+    }
 
-    for transaction in reader.deserialize::<Transaction>().filter_map(Result::ok) {
-        engine.handle_transaction(transaction);
+    /// Reads transactions in `format` from `reader` and applies them, where `input` names
+    /// the reader in errors and the rejection report.
+    pub fn process(
+        &mut self,
+        input: &str,
+        format: InputFormat,
+        reader: impl Read,
+    ) -> anyhow::Result<()> {
+        let shared_input: Arc<str> = Arc::from(input);
+        for row in format.rows(reader)? {
+            let row = row?;
+            let transaction = match row.transaction {
+                Ok(transaction) => transaction,
+                Err(error) if self.strict => anyhow::bail!("{input}, line {}: {error}", row.line),
+                Err(error) => {
+                    if let Some(report) = &mut self.report {
+                        report.reject(input, row.line, &row.record, error.code(), None)?;
+                    }
+                    continue;
+                }
+            };
+            if self.strict
+                && let Err(rejection) = self.engine.config().check_amount(&transaction)
+            {
+                anyhow::bail!("{input}, line {}: {rejection}", row.line);
+            }
+
+            match &mut self.reorder {
+                Some(reorder) => {
+                    reorder.push(
+                        transaction.timestamp,
+                        HeldRow {
+                            input: shared_input.clone(),
+                            line: row.line,
+                            record: row.record,
+                            transaction,
+                        },
+                    );
+                    while let Some(held) = self.reorder.as_mut().and_then(ReorderBuffer::pop_ready)
+                    {
+                        self.apply(&held.input, held.line, &held.record, held.transaction)?;
+                    }
+                }
+                None => self.apply(input, row.line, &row.record, transaction)?,
+            }
+        }
+        Ok(())
     }
 
-    let mut writer = csv::Writer::from_writer(writer);
+    /// Logs and applies one transaction, reporting it if the engine rejects it.
+    fn apply(
+        &mut self,
+        input: &str,
+        line: u64,
+        record: &str,
+        transaction: Transaction,
+    ) -> anyhow::Result<()> {
+        if let Some(log) = &mut self.log {
+            log.append(&transaction)?;
+        }
+        let timestamp = transaction.timestamp;
+        if let Err(rejection) = self.engine.try_handle_transaction(transaction)?
+            && let Some(report) = &mut self.report
+        {
+            report.reject(input, line, record, rejection.code(), timestamp)?;
+        }
+        Ok(())
+    }
 
-    for row in engine.output() {
-        writer.serialize(row)?;
+    /// Applies every transaction still held back for reordering.
+    fn release_all(&mut self) -> anyhow::Result<()> {
+        while let Some(held) = self.reorder.as_mut().and_then(ReorderBuffer::pop) {
+            self.apply(&held.input, held.line, &held.record, held.transaction)?;
+        }
+        Ok(())
     }
 
-    writer.flush()?;
+    /// Writes the resulting accounts in `format` to `writer`.
+    pub fn finish(mut self, format: OutputFormat, writer: impl Write) -> anyhow::Result<()> {
+        self.release_all()?;
+        if let Some(report) = &mut self.report {
+            report.flush()?;
+        }
+        if let Some(log) = &mut self.log {
+            log.sync()?;
+        }
+        self.engine.store_mut().flush()?;
 
-    Ok(())
+        format.write(self.engine.try_output()?, writer)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::run::run;
+    use crate::input::InputFormat;
+    use crate::output::OutputFormat;
+    use crate::report::RejectionReport;
+    use crate::run::{Runner, run};
 
     #[test]
     fn single_deposit() {
         let input = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
         let mut output = Vec::new();
-        let expected_output = b"client,available,held,total,locked\n1,1,0,1,false\n";
+        let expected_output = b"client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
 
-        assert!(run(&input[..], &mut output).is_ok());
+        assert!(run([&input[..]], &mut output).is_ok());
         assert_eq!(output, expected_output);
     }
 
@@ -45,9 +234,54 @@ mod tests {
     fn four_digit_precision() {
         let input = b"type,client,tx,amount\ndeposit,1,1,1.0001\n";
         let mut output = Vec::new();
-        let expected_output = b"client,available,held,total,locked\n1,1.0001,0,1.0001,false\n";
+        let expected_output = b"client,available,held,total,locked\n1,1.0001,0.0000,1.0001,false\n";
+
+        assert!(run([&input[..]], &mut output).is_ok());
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn negative_amounts_are_skipped() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,-5\nwithdrawal,1,3,-5\n";
+        let mut output = Vec::new();
+        let expected_output = b"client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
 
-        assert!(run(&input[..], &mut output).is_ok());
+        assert!(run([&input[..]], &mut output).is_ok());
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn excess_precision_is_skipped() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.00001\n";
+        let mut output = Vec::new();
+        let expected_output = b"client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
+
+        assert!(run([&input[..]], &mut output).is_ok());
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn output_is_ordered_by_client() {
+        let input = b"type,client,tx,amount\ndeposit,3,1,1.0\ndeposit,1,2,1.0\ndeposit,2,3,1.0\n";
+        let mut output = Vec::new();
+        let expected_output = b"client,available,held,total,locked\n\
+1,1.0000,0.0000,1.0000,false\n\
+2,1.0000,0.0000,1.0000,false\n\
+3,1.0000,0.0000,1.0000,false\n";
+
+        assert!(run([&input[..]], &mut output).is_ok());
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn multiple_inputs_share_an_engine() {
+        let first = b"type,client,tx,amount\ndeposit,1,1,2.0\n";
+        let second = b"type,client,tx,amount\nwithdrawal,1,2,0.5\ndispute,1,1,\n";
+        let mut output = Vec::new();
+        let expected_output =
+            b"client,available,held,total,locked\n1,-0.5000,2.0000,1.5000,false\n";
+
+        assert!(run([&first[..], &second[..]], &mut output).is_ok());
         assert_eq!(output, expected_output);
     }
 
@@ -55,9 +289,9 @@ mod tests {
     fn dispute_deposit() {
         let input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1,\n";
         let mut output = Vec::new();
-        let expected_output = b"client,available,held,total,locked\n1,0,1,1,false\n";
+        let expected_output = b"client,available,held,total,locked\n1,0.0000,1.0000,1.0000,false\n";
 
-        assert!(run(&input[..], &mut output).is_ok());
+        assert!(run([&input[..]], &mut output).is_ok());
         assert_eq!(output, expected_output);
     }
 
@@ -65,9 +299,9 @@ mod tests {
     fn dispute_resolve_deposit() {
         let input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1,\nresolve,1,1,\n";
         let mut output = Vec::new();
-        let expected_output = b"client,available,held,total,locked\n1,1,0,1,false\n";
+        let expected_output = b"client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
 
-        assert!(run(&input[..], &mut output).is_ok());
+        assert!(run([&input[..]], &mut output).is_ok());
         assert_eq!(output, expected_output);
     }
 
@@ -75,9 +309,150 @@ mod tests {
     fn dispute_chargeback_deposit() {
         let input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1,\nchargeback,1,1,\n";
         let mut output = Vec::new();
-        let expected_output = b"client,available,held,total,locked\n1,1,0,1,true\n";
+        let expected_output = b"client,available,held,total,locked\n1,0.0000,0.0000,0.0000,true\n";
+
+        assert!(run([&input[..]], &mut output).is_ok());
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn strict_fails_on_invalid_row() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,x\n";
+
+        let mut runner = Runner::default().with_strict(true);
+        assert_eq!(
+            "in.csv, line 3: invalid `amount`: invalid value: string \"x\", expected a Decimal type representing a fixed-point number",
+            runner
+                .process("in.csv", InputFormat::Csv, &input[..])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn strict_fails_on_malformed_amount() {
+        for (row, error) in [
+            (
+                "deposit,1,2,",
+                "deposits and withdrawals require an amount, disputes must not have one",
+            ),
+            (
+                "dispute,1,1,5.0",
+                "deposits and withdrawals require an amount, disputes must not have one",
+            ),
+            (
+                "deposit,1,2,1.00001",
+                "amount has more decimal places than allowed",
+            ),
+        ] {
+            let input = format!("type,client,tx,amount\ndeposit,1,1,1.0\n{row}\n");
+
+            let mut runner = Runner::default().with_strict(true);
+            assert_eq!(
+                format!("in.csv, line 3: {error}"),
+                runner
+                    .process("in.csv", InputFormat::Csv, input.as_bytes())
+                    .unwrap_err()
+                    .to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn strict_skips_rejected_transactions() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,2.0\n";
+        let mut output = Vec::new();
+        let expected_output = b"client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
+
+        let mut runner = Runner::default().with_strict(true);
+        assert!(
+            runner
+                .process("in.csv", InputFormat::Csv, &input[..])
+                .is_ok()
+        );
+        assert!(runner.finish(OutputFormat::Csv, &mut output).is_ok());
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn formats_share_an_engine() {
+        let csv = b"type,client,tx,amount\ndeposit,1,1,2.0\n";
+        let ndjson = br#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "0.5"}"#;
+        let json = br#"[{"type": "dispute", "client": 1, "tx": 1}]"#;
+        let mut output = Vec::new();
+        let expected_output =
+            b"client,available,held,total,locked\n1,-0.5000,2.0000,1.5000,false\n";
+
+        let mut runner = Runner::default();
+        assert!(runner.process("a", InputFormat::Csv, &csv[..]).is_ok());
+        assert!(
+            runner
+                .process("b", InputFormat::Ndjson, &ndjson[..])
+                .is_ok()
+        );
+        assert!(runner.process("c", InputFormat::Json, &json[..]).is_ok());
+        assert!(runner.finish(OutputFormat::Csv, &mut output).is_ok());
+        assert_eq!(output, expected_output);
+    }
+
+    #[test]
+    fn rejected_rows_are_reported() {
+        let input =
+            b"type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,2.0\ndeposit,x,3,1.0\nlemon\n";
+        let mut output = Vec::new();
+        let mut rejections = Vec::new();
+        let expected_rejections = b"input,line,record,reason,timestamp\n\
+in.csv,3,\"withdrawal,1,2,2.0\",insufficient_funds,\n\
+in.csv,4,\"deposit,x,3,1.0\",invalid_field,\n\
+in.csv,5,lemon,wrong_field_count,\n";
+
+        let mut runner = Runner::default().with_report(RejectionReport::new(&mut rejections));
+        assert!(
+            runner
+                .process("in.csv", InputFormat::Csv, &input[..])
+                .is_ok()
+        );
+        assert!(runner.finish(OutputFormat::Csv, &mut output).is_ok());
+        assert_eq!(rejections, expected_rejections);
+    }
+
+    #[test]
+    fn rejections_report_their_timestamp() {
+        let input = b"type,client,tx,amount,timestamp\n\
+deposit,1,1,1.0,2024-01-31T09:30:00.250Z\n\
+withdrawal,1,2,2.0,1706693400500\n";
+        let mut rejections = Vec::new();
+        let expected_rejections = b"input,line,record,reason,timestamp\n\
+in.csv,3,\"withdrawal,1,2,2.0,1706693400500\",insufficient_funds,2024-01-31T09:30:00.500Z\n";
+
+        let mut runner = Runner::default().with_report(RejectionReport::new(&mut rejections));
+        assert!(
+            runner
+                .process("in.csv", InputFormat::Csv, &input[..])
+                .is_ok()
+        );
+        assert!(runner.finish(OutputFormat::Csv, &mut Vec::new()).is_ok());
+        assert_eq!(rejections, expected_rejections);
+    }
+
+    #[test]
+    fn transactions_are_reordered_within_the_window() {
+        let input = b"type,client,tx,amount,timestamp\n\
+deposit,1,1,1.0,1000\n\
+withdrawal,1,3,1.5,1200\n\
+deposit,1,2,1.0,1100\n\
+dispute,1,1,,1300\n";
+        let mut output = Vec::new();
+        let expected_output =
+            b"client,available,held,total,locked\n1,-0.5000,1.0000,0.5000,false\n";
 
-        assert!(run(&input[..], &mut output).is_ok());
+        let mut runner = Runner::default().with_reorder_window(500);
+        assert!(
+            runner
+                .process("in.csv", InputFormat::Csv, &input[..])
+                .is_ok()
+        );
+        assert!(runner.finish(OutputFormat::Csv, &mut output).is_ok());
         assert_eq!(output, expected_output);
     }
 }
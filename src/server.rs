@@ -0,0 +1,188 @@
+use crate::engine::Engine;
+use crate::input::{InputFormat, LineParser};
+use crate::output::OutputFormat;
+use crate::store::{MemoryStore, Store};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+/// The fields of every line a client sends, in order.
+pub const HEADERS: &str = "type,client,tx,amount";
+/// The command that replies with every account as CSV.
+pub const SNAPSHOT_COMMAND: &str = "SNAPSHOT";
+
+/// Applies transactions sent over TCP to one shared [`Engine`].
+///
+/// Each connection sends CSV lines of `type,client,tx,amount` without a header row, and is
+/// answered with one line per transaction: `ok`, or the reason code it was rejected or could
+/// not be read with, such as `insufficient_funds`. Blank lines get no reply. Sending
+/// `SNAPSHOT` instead replies with every account as CSV, followed by a blank line. A line
+/// longer than [`LineParser::MAX_LINE_LEN`] is answered with `line_too_long`, and the
+/// connection closed.
+pub struct Server<S = MemoryStore> {
+    listener: TcpListener,
+    engine: Arc<Mutex<Engine<S>>>,
+}
+
+impl<S: Store + Send + 'static> Server<S> {
+    pub fn bind(address: impl ToSocketAddrs, engine: Engine<S>) -> std::io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(address)?,
+            engine: Arc::new(Mutex::new(engine)),
+        })
+    }
+
+    /// The address being listened on, which names the port chosen when binding to port 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves each connection on its own thread, until accepting one fails.
+    pub fn run(self) -> anyhow::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let engine = self.engine.clone();
+            std::thread::spawn(move || {
+                // A connection that fails, usually by the client going away, only ends itself.
+                let _ = serve(stream, &engine);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve<S: Store>(stream: TcpStream, engine: &Mutex<Engine<S>>) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut parser = LineParser::new(InputFormat::Csv)?.with_headers(HEADERS);
+    let mut line = String::new();
+
+    let limit = LineParser::MAX_LINE_LEN as u64 + 1;
+    while (&mut reader).take(limit).read_line(&mut line)? > 0 {
+        if line.len() > LineParser::MAX_LINE_LEN && !line.ends_with('\n') {
+            writeln!(writer, "line_too_long")?;
+            break;
+        }
+        if line.trim() == SNAPSHOT_COMMAND {
+            let engine = lock(engine)?;
+            OutputFormat::Csv.write(engine.try_output()?, &mut writer)?;
+            writeln!(writer)?;
+        } else if let Some(row) = parser.parse(&line) {
+            let reason = match row.transaction {
+                Ok(transaction) => match lock(engine)?.try_handle_transaction(transaction)? {
+                    Ok(()) => "ok",
+                    Err(rejection) => rejection.code(),
+                },
+                Err(error) => error.code(),
+            };
+            writeln!(writer, "{reason}")?;
+        }
+        line.clear();
+
+        // Replies to pipelined lines are sent together, once every line read so far is answered.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn lock<S>(engine: &Mutex<Engine<S>>) -> anyhow::Result<std::sync::MutexGuard<'_, Engine<S>>> {
+    engine
+        .lock()
+        .map_err(|_| anyhow::anyhow!("another connection panicked"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::input::LineParser;
+    use crate::server::Server;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    fn start() -> SocketAddr {
+        let server = Server::bind("127.0.0.1:0", Engine::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+        address
+    }
+
+    fn connect(address: SocketAddr) -> (BufReader<TcpStream>, TcpStream) {
+        let stream = TcpStream::connect(address).unwrap();
+        (BufReader::new(stream.try_clone().unwrap()), stream)
+    }
+
+    fn replies(reader: &mut BufReader<TcpStream>, count: usize) -> Vec<String> {
+        BufRead::lines(reader)
+            .take(count)
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn every_line_is_answered() {
+        let (mut reader, mut stream) = connect(start());
+
+        stream
+            .write_all(b"deposit,1,1,2.0\n\nwithdrawal, 1, 2, 5.0\r\nlemon\ndispute,1,1,\n")
+            .unwrap();
+
+        assert_eq!(
+            vec!["ok", "insufficient_funds", "wrong_field_count", "ok"],
+            replies(&mut reader, 4)
+        );
+    }
+
+    #[test]
+    fn snapshot_lists_every_account() {
+        let (mut reader, mut stream) = connect(start());
+
+        stream
+            .write_all(b"deposit,2,1,2.0\ndeposit,1,2,1.5\nSNAPSHOT\n")
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                "ok",
+                "ok",
+                "client,available,held,total,locked",
+                "1,1.5000,0.0000,1.5000,false",
+                "2,2.0000,0.0000,2.0000,false",
+                "",
+            ],
+            replies(&mut reader, 6)
+        );
+    }
+
+    #[test]
+    fn overlong_lines_close_the_connection() {
+        let (mut reader, mut stream) = connect(start());
+
+        stream.write_all(b"deposit,1,1,2.0\ndeposit,1,2,").unwrap();
+        stream
+            .write_all(&vec![b'1'; LineParser::MAX_LINE_LEN])
+            .unwrap();
+
+        assert_eq!(vec!["ok", "line_too_long"], replies(&mut reader, 3));
+    }
+
+    #[test]
+    fn connections_share_one_engine() {
+        let address = start();
+
+        let (mut first_reader, mut first) = connect(address);
+        first.write_all(b"deposit,1,1,2.0\n").unwrap();
+        assert_eq!(vec!["ok"], replies(&mut first_reader, 1));
+
+        let (mut second_reader, mut second) = connect(address);
+        second
+            .write_all(b"deposit,1,1,2.0\nwithdrawal,1,2,2.0\n")
+            .unwrap();
+        assert_eq!(
+            vec!["duplicate_transaction", "ok"],
+            replies(&mut second_reader, 2)
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use crate::config::{Config, TimestampOrder};
+use crate::engine::Engine;
+use crate::input::InputFormat;
+use crate::output::{AccountOutput, OutputFormat};
+use crate::snapshot::Snapshot;
+use crate::store::IdRanges;
+use crate::transaction::{Transaction, TransactionType};
+use std::io::{Read, Write};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::thread::JoinHandle;
+
+/// How many transactions are sent to a shard at a time.
+const BATCH_LEN: usize = 1024;
+/// How many batches can wait for each shard before the reading thread blocks.
+const BATCHES_IN_FLIGHT: usize = 16;
+
+/// Applies transactions on several threads, each with its own [`Engine`] holding the clients
+/// whose id is the same modulo the number of shards.
+///
+/// Transactions are read on the calling thread and sent to each shard in batches over a
+/// bounded channel, so a client's transactions are applied in the order they arrived and
+/// reading waits whenever a shard falls behind. The accounts are the same as if one engine had
+/// applied every transaction, since the only state shared between clients, which transaction
+/// ids have been used, is checked here before dispatch.
+///
+/// The latest timestamp applied is shared between clients too, but only known once a shard has
+/// applied a transaction, so configs that depend on it are not supported: monotonic
+/// [`TimestampOrder`] and a [`DisputeWindow::millis`](crate::DisputeWindow::millis) bound.
+pub struct ShardedEngine {
+    config: Config,
+    shards: Vec<Shard>,
+    /// Every id reserved by a deposit or withdrawal, across all shards.
+    reserved: IdRanges,
+    strict: bool,
+}
+
+struct Shard {
+    sender: SyncSender<Vec<Transaction>>,
+    batch: Vec<Transaction>,
+    worker: JoinHandle<Engine>,
+}
+
+/// The engines of a [`ShardedEngine`] once every transaction has been applied.
+pub struct Shards {
+    config: Config,
+    engines: Vec<Engine>,
+}
+
+impl ShardedEngine {
+    /// Starts `shards` empty engines, each on its own thread, that apply transactions under
+    /// `config`.
+    ///
+    /// Panics if `config` depends on the latest timestamp applied, as [`restore`](Self::restore)
+    /// fails.
+    pub fn new(config: Config, shards: usize) -> Self {
+        Self::restore(config, shards, Snapshot::default())
+            .expect("an empty snapshot can always be restored")
+    }
+
+    /// Starts `shards` engines, each on its own thread, holding their share of the state
+    /// captured in `snapshot`.
+    ///
+    /// Fails if `config` depends on the latest timestamp applied, which shards cannot share.
+    pub fn restore(config: Config, shards: usize, snapshot: Snapshot) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            config.timestamp_order == TimestampOrder::Unchecked
+                && config.dispute_window.millis.is_none(),
+            "transactions cannot be ordered or expired by timestamp across shards"
+        );
+        let shards = shards.max(1);
+        let mut reserved = IdRanges::default();
+        let mut parts: Vec<_> = (0..shards)
+            .map(|_| Snapshot {
+                evicted: snapshot.evicted.clone(),
+                latest_timestamp: snapshot.latest_timestamp,
+                ..Snapshot::default()
+            })
+            .collect();
+        for account in snapshot.accounts {
+            parts[usize::from(account.client) % shards]
+                .accounts
+                .push(account);
+        }
+        for transaction in snapshot.transactions {
+            reserved.insert(transaction.tx, transaction.tx);
+            parts[usize::from(transaction.client) % shards]
+                .transactions
+                .push(transaction);
+        }
+        for &(first, last) in &snapshot.evicted {
+            reserved.insert(first, last);
+        }
+
+        let shards = parts
+            .into_iter()
+            .map(|part| {
+                let engine = Engine::restore(config, part)?;
+                let (sender, receiver) = sync_channel(BATCHES_IN_FLIGHT);
+                Ok(Shard {
+                    sender,
+                    batch: Vec::with_capacity(BATCH_LEN),
+                    worker: std::thread::spawn(move || apply(engine, receiver)),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            config,
+            shards,
+            reserved,
+            strict: false,
+        })
+    }
+
+    /// Fails on the first row that cannot be deserialized, or whose amount is missing,
+    /// unexpected or too precise, rather than skipping it.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Reads transactions in `format` from `reader` and dispatches them, where `input` names
+    /// the reader in errors.
+    pub fn process(
+        &mut self,
+        input: &str,
+        format: InputFormat,
+        reader: impl Read,
+    ) -> anyhow::Result<()> {
+        for row in format.rows(reader)? {
+            let row = row?;
+            match row.transaction {
+                Ok(transaction) => {
+                    if self.strict
+                        && let Err(rejection) = self.config.check_amount(&transaction)
+                    {
+                        anyhow::bail!("{input}, line {}: {rejection}", row.line);
+                    }
+                    self.handle_transaction(transaction)?;
+                }
+                Err(error) if self.strict => anyhow::bail!("{input}, line {}: {error}", row.line),
+                Err(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a transaction to its client's shard, unless it would be rejected for reusing an
+    /// id, which only this thread can tell.
+    pub fn handle_transaction(&mut self, transaction: Transaction) -> anyhow::Result<()> {
+        if self.reserves_id(&transaction) {
+            if self.reserved.contains(transaction.tx) {
+                return Ok(());
+            }
+            self.reserved.insert(transaction.tx, transaction.tx);
+        }
+
+        let index = usize::from(transaction.client) % self.shards.len();
+        let shard = &mut self.shards[index];
+        shard.batch.push(transaction);
+        if shard.batch.len() == BATCH_LEN {
+            shard.send()?;
+        }
+        Ok(())
+    }
+
+    /// Whether the engine would reserve the transaction's id, which it does for every deposit
+    /// and withdrawal with an acceptable amount.
+    fn reserves_id(&self, transaction: &Transaction) -> bool {
+        matches!(
+            transaction.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && self.config.check_amount(transaction).is_ok()
+    }
+
+    /// Waits for every shard to apply the transactions sent to it.
+    pub fn join(self) -> anyhow::Result<Shards> {
+        let mut workers = Vec::with_capacity(self.shards.len());
+        for mut shard in self.shards {
+            shard.send()?;
+            drop(shard.sender);
+            workers.push(shard.worker);
+        }
+        let engines = workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("a shard's thread panicked"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Shards {
+            config: self.config,
+            engines,
+        })
+    }
+}
+
+impl Shard {
+    fn send(&mut self) -> anyhow::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_LEN));
+        self.sender
+            .send(batch)
+            .map_err(|_| anyhow::anyhow!("a shard's thread stopped early"))
+    }
+}
+
+fn apply(mut engine: Engine, receiver: Receiver<Vec<Transaction>>) -> Engine {
+    for batch in receiver {
+        for transaction in batch {
+            // Rejections are not reported from shards.
+            let _ = engine.handle_transaction(transaction);
+        }
+    }
+    engine
+}
+
+impl Shards {
+    /// Summarises every account for output, as [`Engine::output`] would for one engine.
+    pub fn output(&self) -> impl Iterator<Item = AccountOutput> + use<> {
+        let mut rows: Vec<_> = self
+            .engines
+            .iter()
+            .flat_map(|engine| engine.output())
+            .collect();
+        rows.sort_by_key(|row| row.client);
+        self.config.output_order.sort(&mut rows);
+        rows.into_iter()
+    }
+
+    /// Captures every shard's accounts and stored transactions as one snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        let mut evicted = IdRanges::default();
+        for engine in &self.engines {
+            let part = engine.snapshot();
+            snapshot.accounts.extend(part.accounts);
+            snapshot.transactions.extend(part.transactions);
+            for (first, last) in part.evicted {
+                evicted.insert(first, last);
+            }
+            snapshot.latest_timestamp = snapshot.latest_timestamp.max(part.latest_timestamp);
+        }
+        snapshot.accounts.sort_by_key(|account| account.client);
+        snapshot
+            .transactions
+            .sort_by_key(|transaction| transaction.tx);
+        snapshot.evicted = evicted.ranges().collect();
+        snapshot
+    }
+
+    /// Writes the resulting accounts in `format` to `writer`.
+    pub fn write(&self, format: OutputFormat, writer: impl Write) -> anyhow::Result<()> {
+        format.write(self.output(), writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, DisputeWindow, TimestampOrder};
+    use crate::engine::Engine;
+    use crate::output::OutputOrder;
+    use crate::shard::ShardedEngine;
+    use crate::snapshot::Snapshot;
+    use crate::transaction::Transaction;
+
+    /// A deterministic mix of every transaction type across a handful of clients, including
+    /// reused ids, disputes naming the wrong client and out of order timestamps.
+    fn transactions() -> Vec<Transaction> {
+        let mut seed: u64 = 0x5eed;
+        let mut next = move |bound: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        (0..20_000)
+            .map(|index| {
+                let client = next(7) as u16;
+                let tx = next(5000) as u32;
+                let amount = (next(1000) + 1) as f64 / 100.0;
+                let transaction = match next(10) {
+                    0..=3 => Transaction::deposit(client, tx, amount),
+                    4..=5 => Transaction::withdrawal(client, tx, amount),
+                    6..=7 => Transaction::dispute(client, tx),
+                    8 => Transaction::resolve(client, tx),
+                    _ => Transaction::chargeback(client, tx),
+                };
+                transaction.at(index * 10 + next(50))
+            })
+            .collect()
+    }
+
+    fn assert_matches_one_engine(config: Config) {
+        let mut engine = Engine::new(config);
+        for transaction in transactions() {
+            let _ = engine.handle_transaction(transaction);
+        }
+
+        for shards in [1, 2, 3, 8] {
+            let mut sharded = ShardedEngine::new(config, shards);
+            for transaction in transactions() {
+                sharded.handle_transaction(transaction).unwrap();
+            }
+            let sharded = sharded.join().unwrap();
+
+            assert_eq!(
+                engine.output().collect::<Vec<_>>(),
+                sharded.output().collect::<Vec<_>>(),
+                "{shards} shards"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_one_engine() {
+        assert_matches_one_engine(Config::default());
+    }
+
+    #[test]
+    fn matches_one_engine_ordered_by_total() {
+        assert_matches_one_engine(Config {
+            output_order: OutputOrder::Total,
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    fn matches_one_engine_with_a_dispute_window() {
+        assert_matches_one_engine(Config {
+            dispute_window: DisputeWindow {
+                transactions: Some(3),
+                millis: None,
+            },
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    fn timestamp_dependent_configs_are_refused() {
+        for config in [
+            Config {
+                timestamp_order: TimestampOrder::Monotonic,
+                ..Config::default()
+            },
+            Config {
+                dispute_window: DisputeWindow {
+                    transactions: None,
+                    millis: Some(2000),
+                },
+                ..Config::default()
+            },
+        ] {
+            assert!(ShardedEngine::restore(config, 2, Snapshot::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn restored_state_is_split_across_shards() {
+        let mut engine = Engine::default();
+        for transaction in transactions().into_iter().take(10_000) {
+            let _ = engine.handle_transaction(transaction);
+        }
+        let mut sharded = ShardedEngine::restore(Config::default(), 3, engine.snapshot()).unwrap();
+        for transaction in transactions().into_iter().skip(10_000) {
+            let _ = engine.handle_transaction(transaction.clone());
+            sharded.handle_transaction(transaction).unwrap();
+        }
+        let sharded = sharded.join().unwrap();
+
+        assert_eq!(engine.snapshot(), sharded.snapshot());
+    }
+}
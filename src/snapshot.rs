@@ -0,0 +1,294 @@
+use crate::account::Account;
+use crate::state::{StoredTransaction, TransactionState};
+use crate::transaction::{ClientID, Timestamp, TransactionID};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// The snapshot format written by this version. Bump it whenever the format changes, and
+/// teach [`Snapshot::read`] to migrate the previous version.
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+/// Everything an [`Engine`](crate::Engine) needs to carry on where it left off: accounts,
+/// stored transactions with their dispute states, lock flags, and the latest timestamp applied.
+///
+/// Written as JSON with a format version, and with amounts as strings so they keep their exact
+/// value.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub accounts: Vec<AccountSnapshot>,
+    pub transactions: Vec<TransactionSnapshot>,
+    /// Ids of transactions evicted once outside the [`DisputeWindow`](crate::DisputeWindow),
+    /// as inclusive ranges.
+    #[serde(default)]
+    pub evicted: Vec<(TransactionID, TransactionID)>,
+    /// The latest timestamp of an applied transaction, which later ones are ordered and expired
+    /// against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latest_timestamp: Option<Timestamp>,
+    /// The last [`WriteAheadLog`](crate::WriteAheadLog) record included in the snapshot, or
+    /// zero if none are.
+    #[serde(default)]
+    pub log_sequence: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccountSnapshot {
+    pub client: ClientID,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+    #[serde(default)]
+    pub transactions: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TransactionSnapshot {
+    pub tx: TransactionID,
+    pub client: ClientID,
+    /// Positive for deposits, negative for withdrawals.
+    pub amount: Decimal,
+    pub state: TransactionState,
+    #[serde(default)]
+    pub sequence: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+}
+
+#[derive(Serialize)]
+struct Versioned<'s> {
+    version: u32,
+    accounts: &'s [AccountSnapshot],
+    transactions: &'s [TransactionSnapshot],
+    evicted: &'s [(TransactionID, TransactionID)],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latest_timestamp: Option<Timestamp>,
+    log_sequence: u64,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+impl Snapshot {
+    /// Reads a snapshot written by this or an earlier version.
+    pub fn read(reader: impl Read) -> anyhow::Result<Self> {
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let Version { version } = Version::deserialize(&value)?;
+        match version {
+            // Version 1 predates the write-ahead log, so has no `log_sequence` and takes the
+            // default of zero. Versions 1 and 2 predate the dispute window, so have no evicted
+            // ids, and count an account's transactions from zero once restored.
+            1..=3 => {
+                // Versions before 4 predate the saved clock, so take it from the latest applied
+                // transaction kept.
+                let mut snapshot = Snapshot::deserialize(&value)?;
+                snapshot.latest_timestamp = snapshot
+                    .transactions
+                    .iter()
+                    .filter(|transaction| transaction.state != TransactionState::Declined)
+                    .filter_map(|transaction| transaction.timestamp)
+                    .max();
+                Ok(snapshot)
+            }
+            SNAPSHOT_VERSION => Ok(Snapshot::deserialize(&value)?),
+            _ => anyhow::bail!(
+                "unsupported snapshot version {version}, expected at most {SNAPSHOT_VERSION}"
+            ),
+        }
+    }
+
+    /// Writes the snapshot at the current [`SNAPSHOT_VERSION`].
+    pub fn write(&self, mut writer: impl Write) -> anyhow::Result<()> {
+        serde_json::to_writer(
+            &mut writer,
+            &Versioned {
+                version: SNAPSHOT_VERSION,
+                accounts: &self.accounts,
+                transactions: &self.transactions,
+                evicted: &self.evicted,
+                latest_timestamp: self.latest_timestamp,
+                log_sequence: self.log_sequence,
+            },
+        )?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl From<(ClientID, Account)> for AccountSnapshot {
+    fn from((client, account): (ClientID, Account)) -> Self {
+        Self {
+            client,
+            available: account.available,
+            held: account.held,
+            locked: account.locked,
+            transactions: account.transactions,
+        }
+    }
+}
+
+impl From<(TransactionID, StoredTransaction)> for TransactionSnapshot {
+    fn from((tx, transaction): (TransactionID, StoredTransaction)) -> Self {
+        Self {
+            tx,
+            client: transaction.client,
+            amount: transaction.amount,
+            state: transaction.state,
+            sequence: transaction.sequence,
+            timestamp: transaction.timestamp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, TimestampOrder};
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::snapshot::Snapshot;
+    use crate::state::TransactionState;
+    use crate::transaction::{Timestamp, Transaction};
+
+    fn engine() -> Engine {
+        let mut engine = Engine::default();
+        for transaction in [
+            Transaction::deposit(1, 1, 1.5),
+            Transaction::deposit(1, 2, 2.0),
+            Transaction::dispute(1, 2),
+            Transaction::deposit(2, 3, 1.0),
+            Transaction::dispute(2, 3),
+            Transaction::chargeback(2, 3),
+        ] {
+            engine.handle_transaction(transaction).unwrap();
+        }
+        assert_eq!(
+            Err(Rejection::InsufficientFunds),
+            engine.handle_transaction(Transaction::withdrawal(1, 4, 5.0))
+        );
+        engine
+    }
+
+    fn round_trip(engine: &Engine) -> Engine {
+        let mut file = Vec::new();
+        engine.snapshot().write(&mut file).unwrap();
+        Engine::restore(Config::default(), Snapshot::read(&file[..]).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn format_is_versioned_json() {
+        let mut file = Vec::new();
+        engine().snapshot().write(&mut file).unwrap();
+
+        assert_eq!(
+            r#"{"version":4,"accounts":[{"client":1,"available":"1.5","held":"2","locked":false,"transactions":2},{"client":2,"available":"0","held":"0","locked":true,"transactions":1}],"transactions":[{"tx":1,"client":1,"amount":"1.5","state":"processed","sequence":1},{"tx":2,"client":1,"amount":"2","state":"disputed","sequence":2},{"tx":3,"client":2,"amount":"1","state":"charged_back","sequence":1},{"tx":4,"client":1,"amount":"-5","state":"declined","sequence":0}],"evicted":[],"log_sequence":0}
+"#,
+            String::from_utf8(file).unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trip_keeps_accounts_and_transactions() {
+        let engine = engine();
+        let restored = round_trip(&engine);
+
+        assert_eq!(engine.snapshot(), restored.snapshot());
+        assert_eq!(
+            TransactionState::ChargedBack,
+            restored.transaction(3).unwrap().state
+        );
+        assert!(restored.account(2).unwrap().locked);
+    }
+
+    #[test]
+    fn restored_transactions_can_be_settled() {
+        let mut restored = round_trip(&engine());
+
+        restored
+            .handle_transaction(Transaction::resolve(1, 2))
+            .unwrap();
+        restored
+            .handle_transaction(Transaction::dispute(1, 1))
+            .unwrap();
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            restored.handle_transaction(Transaction::deposit(1, 4, 1.0))
+        );
+        assert_eq!(
+            Err(Rejection::AccountLocked),
+            restored.handle_transaction(Transaction::deposit(2, 5, 1.0))
+        );
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let file = br#"{"version":5,"accounts":[],"transactions":[],"log_sequence":0}"#;
+
+        assert_eq!(
+            "unsupported snapshot version 5, expected at most 4",
+            Snapshot::read(&file[..]).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn version_1_is_migrated() {
+        let file = br#"{"version":1,"accounts":[{"client":1,"available":"1","held":"0","locked":false}],"transactions":[{"tx":1,"client":1,"amount":"1","state":"processed"}]}"#;
+        let snapshot = Snapshot::read(&file[..]).unwrap();
+
+        assert_eq!(0, snapshot.log_sequence);
+        assert_eq!(1, snapshot.transactions.len());
+    }
+
+    #[test]
+    fn version_3_takes_its_clock_from_applied_transactions() {
+        let file = br#"{"version":3,"accounts":[{"client":1,"available":"1","held":"0","locked":false,"transactions":1}],"transactions":[{"tx":1,"client":1,"amount":"1","state":"processed","sequence":1,"timestamp":2000},{"tx":2,"client":1,"amount":"1","state":"declined","sequence":0,"timestamp":3000}],"evicted":[],"log_sequence":0}"#;
+        let snapshot = Snapshot::read(&file[..]).unwrap();
+
+        assert_eq!(
+            Some(Timestamp::from_millis(2000)),
+            snapshot.latest_timestamp
+        );
+    }
+
+    #[test]
+    fn restored_clock_rejects_earlier_timestamps() {
+        let config = Config {
+            timestamp_order: TimestampOrder::Monotonic,
+            ..Config::default()
+        };
+        let mut engine = Engine::new(config);
+        engine
+            .handle_transaction(Transaction::deposit(1, 1, 1.0).at(2000))
+            .unwrap();
+        let mut file = Vec::new();
+        engine.snapshot().write(&mut file).unwrap();
+
+        let mut restored = Engine::restore(config, Snapshot::read(&file[..]).unwrap()).unwrap();
+        assert_eq!(
+            Err(Rejection::OutOfOrder),
+            restored.handle_transaction(Transaction::deposit(1, 2, 1.0).at(1000))
+        );
+    }
+
+    #[test]
+    fn missing_version() {
+        let file = br#"{"accounts":[],"transactions":[]}"#;
+
+        assert!(Snapshot::read(&file[..]).is_err());
+    }
+
+    #[test]
+    fn duplicate_transaction_is_an_error() {
+        let file = br#"{"version":1,"accounts":[{"client":1,"available":"1","held":"0","locked":false}],"transactions":[{"tx":1,"client":1,"amount":"1","state":"processed"},{"tx":1,"client":1,"amount":"1","state":"processed"}]}"#;
+
+        assert_eq!(
+            "snapshot has transaction 1 more than once",
+            Engine::restore(Config::default(), Snapshot::read(&file[..]).unwrap())
+                .err()
+                .unwrap()
+                .to_string()
+        );
+    }
+}
@@ -0,0 +1,675 @@
+use crate::account::Account;
+use crate::state::{StoredTransaction, TransactionState};
+use crate::store::{IdRanges, Store};
+use crate::transaction::{ClientID, Timestamp, TransactionID};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A rough upper bound on the memory each in-memory transaction costs, including the map's
+/// spare capacity and the spill queue.
+pub const BYTES_PER_TRANSACTION: usize = 96;
+
+/// Bytes per spilled transaction: its id, client, amount, state, sequence, and timestamp with a
+/// flag for whether it has one.
+const RECORD_LEN: usize = 4 + 2 + 16 + 1 + 8 + 1 + 8;
+
+/// Bits of a run's [`IdFilter`] per transaction, which with [`FILTER_HASHES`] makes about one
+/// lookup in a hundred for an id the run lacks read it from disk anyway.
+const FILTER_BITS_PER_TRANSACTION: u64 = 10;
+
+const FILTER_HASHES: u64 = 7;
+
+/// Keeps accounts in memory, but only the most recently written transactions, spilling older
+/// ones to sorted files on disk and reading them back when a late dispute arrives.
+///
+/// A transaction updated after it spilled, say by a dispute, is held in memory again and
+/// shadows its spilled copy. Spilled files are merged as they accumulate, keeping the newest
+/// copy of each transaction, so a lookup only searches a handful of them. Each file keeps a
+/// filter of its ids in memory, a little over a byte per transaction, so looking up a new id
+/// rarely touches disk. The files are deleted when the store is dropped.
+pub struct SpillStore {
+    accounts: BTreeMap<ClientID, Account>,
+    hot: HashMap<TransactionID, StoredTransaction>,
+    /// Transactions in `hot`, oldest first.
+    queue: VecDeque<TransactionID>,
+    capacity: usize,
+    directory: PathBuf,
+    /// Spilled transactions, oldest first.
+    runs: Vec<Run>,
+    next_run: usize,
+    evicted: IdRanges,
+    latest_timestamp: Option<Timestamp>,
+}
+
+/// A file of transactions sorted by id, each at most once.
+struct Run {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    first: TransactionID,
+    last: TransactionID,
+    filter: IdFilter,
+}
+
+/// A Bloom filter of the ids in a [`Run`], which can say an id is in the run when it is not,
+/// but never the other way round.
+struct IdFilter {
+    bits: Vec<u64>,
+}
+
+impl SpillStore {
+    /// Creates a store that holds at most `capacity` transactions in memory, spilling the rest
+    /// to a new directory inside `parent`.
+    pub fn new(parent: &Path, capacity: usize) -> std::io::Result<Self> {
+        static STORES: AtomicUsize = AtomicUsize::new(0);
+        let directory = parent.join(format!(
+            "toy-engine-spill-{}-{}",
+            std::process::id(),
+            STORES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            accounts: BTreeMap::new(),
+            hot: HashMap::new(),
+            queue: VecDeque::new(),
+            capacity: capacity.max(1),
+            directory,
+            runs: Vec::new(),
+            next_run: 0,
+            evicted: IdRanges::default(),
+            latest_timestamp: None,
+        })
+    }
+
+    /// Creates a store whose in-memory transactions take roughly at most `bytes`.
+    pub fn with_memory_limit(parent: &Path, bytes: usize) -> std::io::Result<Self> {
+        Self::new(parent, bytes / BYTES_PER_TRANSACTION)
+    }
+
+    /// The number of transactions held in memory.
+    pub fn in_memory(&self) -> usize {
+        self.hot.len()
+    }
+
+    /// The number of files transactions have spilled to.
+    pub fn spilled_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Spills the older half of the in-memory transactions to a new run.
+    fn spill(&mut self) -> std::io::Result<()> {
+        let count = self.queue.len().div_ceil(2);
+        let mut spilled: Vec<_> = self
+            .queue
+            .drain(..count)
+            .map(|tx| {
+                (
+                    tx,
+                    self.hot.remove(&tx).expect("queued transactions are held"),
+                )
+            })
+            .collect();
+        spilled.sort_unstable_by_key(|(tx, _)| *tx);
+        let len = spilled.len() as u64;
+        let run = Run::write(self.run_path(), len, spilled.into_iter().map(Ok))?;
+        self.runs.push(run);
+        self.compact()
+    }
+
+    /// Merges the newest two runs while the newer is at least as long as the older, so there
+    /// are only ever logarithmically many runs.
+    fn compact(&mut self) -> std::io::Result<()> {
+        while let [.., older, newer] = &self.runs[..] {
+            if newer.len < older.len {
+                break;
+            }
+            let newer = self.runs.pop().expect("matched two runs");
+            let older = self.runs.pop().expect("matched two runs");
+            let merged = merge(older.records()?, newer.records()?);
+            let run = Run::write(self.run_path(), older.len + newer.len, merged)?;
+            self.runs.push(run);
+            older.remove();
+            newer.remove();
+        }
+        Ok(())
+    }
+
+    fn run_path(&mut self) -> PathBuf {
+        let path = self.directory.join(format!("{}.run", self.next_run));
+        self.next_run += 1;
+        path
+    }
+}
+
+impl Run {
+    /// Writes `records` to a new run, where `capacity` is at least how many there are.
+    fn write(
+        path: PathBuf,
+        capacity: u64,
+        records: impl Iterator<Item = std::io::Result<(TransactionID, StoredTransaction)>>,
+    ) -> std::io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let mut filter = IdFilter::with_capacity(capacity);
+        let mut bounds = None;
+        let mut len = 0;
+        for record in records {
+            let (tx, transaction) = record?;
+            writer.write_all(&encode(tx, &transaction))?;
+            filter.insert(tx);
+            bounds = Some((bounds.map_or(tx, |(first, _)| first), tx));
+            len += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        let (first, last) = bounds.unwrap_or((TransactionID::MAX, TransactionID::MIN));
+        Ok(Run {
+            file: File::open(&path)?,
+            path,
+            len,
+            first,
+            last,
+            filter,
+        })
+    }
+
+    /// Binary searches the run for `tx`.
+    fn find(&self, tx: TransactionID) -> std::io::Result<Option<StoredTransaction>> {
+        if tx < self.first || tx > self.last || !self.filter.may_contain(tx) {
+            return Ok(None);
+        }
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let (found, transaction) = self.read(middle)?;
+            match found.cmp(&tx) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(Some(transaction)),
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(&self, index: u64) -> std::io::Result<(TransactionID, StoredTransaction)> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(index * RECORD_LEN as u64))?;
+        let mut record = [0; RECORD_LEN];
+        file.read_exact(&mut record)?;
+        decode(&record)
+    }
+
+    /// Every record in the run, in order.
+    fn records(
+        &self,
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<(TransactionID, StoredTransaction)>>>
+    {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut remaining = self.len;
+        Ok(std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let mut record = [0; RECORD_LEN];
+            Some(
+                reader
+                    .read_exact(&mut record)
+                    .and_then(|()| decode(&record)),
+            )
+        }))
+    }
+
+    fn remove(self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl IdFilter {
+    fn with_capacity(transactions: u64) -> Self {
+        let words = (transactions * FILTER_BITS_PER_TRANSACTION)
+            .div_ceil(64)
+            .max(1);
+        Self {
+            bits: vec![0; words as usize],
+        }
+    }
+
+    fn insert(&mut self, tx: TransactionID) {
+        for bit in self.bits_for(tx) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn may_contain(&self, tx: TransactionID) -> bool {
+        self.bits_for(tx)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits set for `tx`, derived from two hashes of it.
+    fn bits_for(&self, tx: TransactionID) -> impl Iterator<Item = usize> + use<> {
+        let len = self.bits.len() as u64 * 64;
+        let first = mix(u64::from(tx));
+        let second = mix(first) | 1;
+        (0..FILTER_HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+    }
+}
+
+/// Scrambles the bits of `value`, as the finaliser of SplitMix64.
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+impl Drop for SpillStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// Merges two sorted runs, keeping `newer`'s copy of any transaction in both.
+fn merge(
+    older: impl Iterator<Item = std::io::Result<(TransactionID, StoredTransaction)>>,
+    newer: impl Iterator<Item = std::io::Result<(TransactionID, StoredTransaction)>>,
+) -> impl Iterator<Item = std::io::Result<(TransactionID, StoredTransaction)>> {
+    let mut older = older.peekable();
+    let mut newer = newer.peekable();
+    std::iter::from_fn(move || {
+        let order = match (older.peek(), newer.peek()) {
+            (Some(Ok((old, _))), Some(Ok((new, _)))) => old.cmp(new),
+            (Some(Err(_)), _) | (Some(_), None) => std::cmp::Ordering::Less,
+            (_, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => return None,
+        };
+        match order {
+            std::cmp::Ordering::Less => older.next(),
+            std::cmp::Ordering::Greater => newer.next(),
+            std::cmp::Ordering::Equal => {
+                older.next();
+                newer.next()
+            }
+        }
+    })
+}
+
+fn encode(tx: TransactionID, transaction: &StoredTransaction) -> [u8; RECORD_LEN] {
+    let mut record = [0; RECORD_LEN];
+    record[0..4].copy_from_slice(&tx.to_le_bytes());
+    record[4..6].copy_from_slice(&transaction.client.to_le_bytes());
+    record[6..22].copy_from_slice(&transaction.amount.serialize());
+    record[22] = match transaction.state {
+        TransactionState::Declined => 0,
+        TransactionState::Processed => 1,
+        TransactionState::Disputed => 2,
+        TransactionState::Resolved => 3,
+        TransactionState::ChargedBack => 4,
+    };
+    record[23..31].copy_from_slice(&transaction.sequence.to_le_bytes());
+    if let Some(timestamp) = transaction.timestamp {
+        record[31] = 1;
+        record[32..40].copy_from_slice(&timestamp.millis().to_le_bytes());
+    }
+    record
+}
+
+fn decode(record: &[u8; RECORD_LEN]) -> std::io::Result<(TransactionID, StoredTransaction)> {
+    let state = match record[22] {
+        0 => TransactionState::Declined,
+        1 => TransactionState::Processed,
+        2 => TransactionState::Disputed,
+        3 => TransactionState::Resolved,
+        4 => TransactionState::ChargedBack,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "spilled transaction has an unknown state",
+            ));
+        }
+    };
+    let mut amount = [0; 16];
+    amount.copy_from_slice(&record[6..22]);
+    let mut sequence = [0; 8];
+    sequence.copy_from_slice(&record[23..31]);
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&record[32..40]);
+    Ok((
+        u32::from_le_bytes([record[0], record[1], record[2], record[3]]),
+        StoredTransaction {
+            client: u16::from_le_bytes([record[4], record[5]]),
+            amount: Decimal::deserialize(amount),
+            state,
+            sequence: u64::from_le_bytes(sequence),
+            timestamp: (record[31] == 1)
+                .then(|| Timestamp::from_millis(u64::from_le_bytes(timestamp))),
+        },
+    ))
+}
+
+impl Store for SpillStore {
+    type Error = std::io::Error;
+
+    fn account(&self, client: ClientID) -> std::io::Result<Option<Account>> {
+        Ok(self.accounts.get(&client).copied())
+    }
+
+    fn insert_account(&mut self, client: ClientID, account: Account) -> std::io::Result<()> {
+        self.accounts.insert(client, account);
+        Ok(())
+    }
+
+    fn accounts(&self) -> std::io::Result<Vec<(ClientID, Account)>> {
+        Ok(self
+            .accounts
+            .iter()
+            .map(|(client, account)| (*client, *account))
+            .collect())
+    }
+
+    fn transaction(&self, tx: TransactionID) -> std::io::Result<Option<StoredTransaction>> {
+        if let Some(transaction) = self.hot.get(&tx) {
+            return Ok(Some(*transaction));
+        }
+        for run in self.runs.iter().rev() {
+            if let Some(transaction) = run.find(tx)? {
+                return Ok(Some(transaction));
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert_transaction(
+        &mut self,
+        tx: TransactionID,
+        transaction: StoredTransaction,
+    ) -> std::io::Result<()> {
+        if self.hot.insert(tx, transaction).is_none() {
+            self.queue.push_back(tx);
+        }
+        if self.hot.len() > self.capacity {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Reads every spilled transaction back into memory, so is only suitable for snapshots of
+    /// histories that fit.
+    fn transactions(&self) -> std::io::Result<Vec<(TransactionID, StoredTransaction)>> {
+        let mut transactions = BTreeMap::new();
+        for run in &self.runs {
+            for record in run.records()? {
+                let (tx, transaction) = record?;
+                transactions.insert(tx, transaction);
+            }
+        }
+        transactions.extend(self.hot.iter().map(|(tx, transaction)| (*tx, *transaction)));
+        Ok(transactions.into_iter().collect())
+    }
+
+    /// Rewrites every spilled run into one without the evicted transactions, so costs as much
+    /// as the history on disk.
+    fn evict(
+        &mut self,
+        expired: &mut dyn FnMut(TransactionID, &StoredTransaction) -> bool,
+    ) -> std::io::Result<usize> {
+        let mut evicted = Vec::new();
+        self.hot.retain(|tx, transaction| {
+            let expired = expired(*tx, transaction);
+            if expired {
+                evicted.push(*tx);
+            }
+            !expired
+        });
+        for &tx in &evicted {
+            self.evicted.insert(tx, tx);
+        }
+        if !evicted.is_empty() {
+            self.queue.retain(|tx| self.hot.contains_key(tx));
+        }
+
+        let runs = std::mem::take(&mut self.runs);
+        if !runs.is_empty() {
+            let capacity = runs.iter().map(|run| run.len).sum();
+            let mut records: Box<dyn Iterator<Item = _>> = Box::new(std::iter::empty());
+            for run in &runs {
+                records = Box::new(merge(records, run.records()?));
+            }
+            let path = self.run_path();
+            // Spilled copies shadowed by one in memory are dropped too, as they are stale.
+            let (hot, evicted) = (&self.hot, &mut self.evicted);
+            let records = records.filter(|record| match record {
+                Ok((tx, transaction)) => {
+                    if hot.contains_key(tx) || evicted.contains(*tx) {
+                        return false;
+                    }
+                    let expired = expired(*tx, transaction);
+                    if expired {
+                        evicted.insert(*tx, *tx);
+                    }
+                    !expired
+                }
+                Err(_) => true,
+            });
+            let run = Run::write(path, capacity, records)?;
+            for run in runs {
+                run.remove();
+            }
+            if run.len > 0 {
+                self.runs.push(run);
+            } else {
+                run.remove();
+            }
+        }
+
+        let spilled: u64 = self.runs.iter().map(|run| run.len).sum();
+        Ok(self.hot.len() + spilled as usize)
+    }
+
+    fn is_evicted(&self, tx: TransactionID) -> std::io::Result<bool> {
+        Ok(self.evicted.contains(tx))
+    }
+
+    fn evicted(&self) -> std::io::Result<Vec<(TransactionID, TransactionID)>> {
+        Ok(self.evicted.ranges().collect())
+    }
+
+    fn insert_evicted(&mut self, first: TransactionID, last: TransactionID) -> std::io::Result<()> {
+        self.evicted.insert(first, last);
+        Ok(())
+    }
+
+    fn latest_timestamp(&self) -> std::io::Result<Option<Timestamp>> {
+        Ok(self.latest_timestamp)
+    }
+
+    fn set_latest_timestamp(&mut self, timestamp: Timestamp) -> std::io::Result<()> {
+        self.latest_timestamp = Some(timestamp);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, DisputeWindow};
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::spill::{IdFilter, SpillStore};
+    use crate::state::TransactionState;
+    use crate::store::Store;
+    use crate::transaction::Transaction;
+
+    fn spilling_engine(capacity: usize) -> Engine<SpillStore> {
+        let store = SpillStore::new(&std::env::temp_dir(), capacity).unwrap();
+        Engine::with_store(Config::default(), store)
+    }
+
+    /// Deposits into ten clients, then disputes, resolves and charges back a spread of them.
+    fn transactions() -> Vec<Transaction> {
+        let mut transactions: Vec<_> = (1..=500)
+            .map(|tx| Transaction::deposit((tx % 10) as u16, tx, 1.0))
+            .collect();
+        transactions.push(Transaction::withdrawal(1, 501, 1000.0));
+        for tx in (3..500).step_by(7) {
+            transactions.push(Transaction::dispute((tx % 10) as u16, tx));
+        }
+        for tx in (3..500).step_by(14) {
+            transactions.push(Transaction::resolve((tx % 10) as u16, tx));
+        }
+        transactions.push(Transaction::chargeback(3, 3 + 7 * 10));
+        transactions
+    }
+
+    #[test]
+    fn matches_the_memory_store() {
+        let mut memory = Engine::default();
+        let mut spilling = spilling_engine(16);
+
+        for transaction in transactions() {
+            let expected = memory.handle_transaction(transaction.clone());
+            assert_eq!(
+                expected,
+                spilling.try_handle_transaction(transaction).unwrap()
+            );
+        }
+        assert_eq!(memory.snapshot(), spilling.try_snapshot().unwrap());
+        assert!(spilling.store().in_memory() <= 16);
+        assert!(spilling.store().spilled_runs() > 1);
+    }
+
+    #[test]
+    fn filter_never_misses_an_id() {
+        let mut filter = IdFilter::with_capacity(1000);
+        let ids: Vec<_> = (0..1000).map(|i| i * 7919 % 100_003).collect();
+        for &tx in &ids {
+            filter.insert(tx);
+        }
+
+        assert!(ids.iter().all(|&tx| filter.may_contain(tx)));
+        let false_positives = (200_000..210_000)
+            .filter(|&tx| filter.may_contain(tx))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn spilled_ids_out_of_order_are_duplicates() {
+        let mut engine = spilling_engine(8);
+        let ids: Vec<_> = (1..=200).map(|i| i * 7919 % 100_003).collect();
+        for &tx in &ids {
+            engine
+                .try_handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap()
+                .unwrap();
+        }
+
+        assert!(engine.store().spilled_runs() > 1);
+        for &tx in &ids {
+            assert_eq!(
+                Err(Rejection::DuplicateTransaction),
+                engine
+                    .try_handle_transaction(Transaction::deposit(1, tx, 1.0))
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn late_dispute_reads_a_spilled_transaction() {
+        let mut engine = spilling_engine(4);
+        for tx in 1..=100 {
+            engine
+                .try_handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap()
+                .unwrap();
+        }
+
+        assert_eq!(
+            Ok(()),
+            engine
+                .try_handle_transaction(Transaction::dispute(1, 1))
+                .unwrap()
+        );
+        assert_eq!(
+            Some(TransactionState::Disputed),
+            engine.store().transaction(1).unwrap().map(|tx| tx.state)
+        );
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine
+                .try_handle_transaction(Transaction::deposit(1, 2, 1.0))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn runs_are_merged() {
+        let mut engine = spilling_engine(2);
+        for tx in 1..=1000 {
+            engine
+                .try_handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap()
+                .unwrap();
+        }
+
+        assert!(engine.store().spilled_runs() <= 10);
+    }
+
+    #[test]
+    fn files_are_removed_on_drop() {
+        let mut engine = spilling_engine(1);
+        for tx in 1..=10 {
+            engine
+                .try_handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap()
+                .unwrap();
+        }
+        let directory = engine.store().directory.clone();
+        assert!(directory.read_dir().unwrap().next().is_some());
+
+        drop(engine);
+        assert!(!directory.exists());
+    }
+
+    #[test]
+    fn eviction_rewrites_spilled_runs() {
+        let store = SpillStore::new(&std::env::temp_dir(), 4).unwrap();
+        let config = Config {
+            dispute_window: DisputeWindow {
+                transactions: Some(11),
+                millis: None,
+            },
+            ..Config::default()
+        };
+        let mut engine = Engine::with_store(config, store);
+        for tx in 1..=100 {
+            engine
+                .try_handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap()
+                .unwrap();
+        }
+        engine
+            .try_handle_transaction(Transaction::dispute(1, 92))
+            .unwrap()
+            .unwrap();
+        engine.try_evict_expired().unwrap();
+
+        assert_eq!(11, engine.store().transactions().unwrap().len());
+        assert_eq!(vec![(1, 89)], engine.store().evicted().unwrap());
+        assert_eq!(
+            Err(Rejection::DisputeExpired),
+            engine
+                .try_handle_transaction(Transaction::dispute(1, 50))
+                .unwrap()
+        );
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine
+                .try_handle_transaction(Transaction::deposit(1, 1, 1.0))
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,586 @@
+use crate::account::Account;
+use crate::state::{StoredTransaction, TransactionState};
+use crate::store::{IdRanges, Store};
+use crate::transaction::{ClientID, Timestamp, TransactionID};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use rust_decimal::Decimal;
+use std::path::Path;
+use std::str::FromStr;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        client INTEGER PRIMARY KEY,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        locked INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS transactions (
+        tx INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        amount TEXT NOT NULL,
+        state TEXT NOT NULL
+    );
+";
+
+/// Changes to [`SCHEMA`], in order. A database records how many it has had applied as its
+/// `user_version`.
+const MIGRATIONS: &[&str] = &[
+    // The dispute window.
+    "
+    ALTER TABLE accounts ADD COLUMN transactions INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE transactions ADD COLUMN sequence INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE transactions ADD COLUMN timestamp INTEGER;
+    CREATE TABLE evicted (
+        first INTEGER PRIMARY KEY,
+        last INTEGER NOT NULL
+    );
+    ",
+    // The engine's clock, which databases from before it take from their applied transactions.
+    "
+    CREATE TABLE clock (
+        latest_timestamp INTEGER NOT NULL
+    );
+    INSERT INTO clock
+        SELECT MAX(timestamp) FROM transactions WHERE state != 'declined'
+        HAVING MAX(timestamp) IS NOT NULL;
+    ",
+];
+
+/// Keeps accounts and stored transactions in an embedded SQLite database, so they outlive the
+/// process and can be queried with SQL.
+///
+/// Amounts are stored as text so they keep their exact value, e.g.
+/// `SELECT client, CAST(available AS REAL) FROM accounts`. Writes are batched into one SQLite
+/// transaction that is only committed on [`flush`](Store::flush); anything written since is
+/// rolled back if the store is dropped first.
+pub struct SqliteStore {
+    connection: Connection,
+    /// The `evicted` table, which is only written back on flush.
+    evicted: IdRanges,
+    /// The `clock` table, which is only written back on flush.
+    latest_timestamp: Option<Timestamp>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        Self::new(connection)
+    }
+
+    /// Opens a database that lives only as long as the store.
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch("BEGIN")?;
+        connection.execute_batch(SCHEMA)?;
+        let applied: usize =
+            connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for migration in MIGRATIONS.iter().skip(applied) {
+            connection.execute_batch(migration)?;
+        }
+        connection.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        connection.execute_batch("COMMIT; BEGIN")?;
+        // Ids found to be expired while evicting, so they can be deleted together.
+        connection.execute_batch("CREATE TEMP TABLE expired (tx INTEGER PRIMARY KEY)")?;
+
+        let mut evicted = IdRanges::default();
+        for range in connection
+            .prepare("SELECT first, last FROM evicted")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        {
+            let (first, last) = range?;
+            evicted.insert(first, last);
+        }
+        let latest_timestamp = connection
+            .query_row("SELECT latest_timestamp FROM clock", [], |row| row.get(0))
+            .optional()?;
+        Ok(Self {
+            connection,
+            evicted,
+            latest_timestamp,
+        })
+    }
+
+    fn read_transaction(row: &Row<'_>) -> rusqlite::Result<StoredTransaction> {
+        Ok(StoredTransaction {
+            client: row.get("client")?,
+            amount: row.get::<_, Amount>("amount")?.0,
+            state: row.get("state")?,
+            sequence: row.get("sequence")?,
+            timestamp: row.get("timestamp")?,
+        })
+    }
+}
+
+impl Store for SqliteStore {
+    type Error = rusqlite::Error;
+
+    fn account(&self, client: ClientID) -> rusqlite::Result<Option<Account>> {
+        self.connection
+            .prepare_cached(
+                "SELECT available, held, locked, transactions FROM accounts WHERE client = ?1",
+            )?
+            .query_row([client], |row| {
+                Ok(Account {
+                    available: row.get::<_, Amount>(0)?.0,
+                    held: row.get::<_, Amount>(1)?.0,
+                    locked: row.get(2)?,
+                    transactions: row.get(3)?,
+                })
+            })
+            .optional()
+    }
+
+    fn insert_account(&mut self, client: ClientID, account: Account) -> rusqlite::Result<()> {
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO accounts (client, available, held, locked, transactions)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![
+                client,
+                Amount(account.available),
+                Amount(account.held),
+                account.locked,
+                account.transactions
+            ])?;
+        Ok(())
+    }
+
+    fn accounts(&self) -> rusqlite::Result<Vec<(ClientID, Account)>> {
+        self.connection
+            .prepare_cached(
+                "SELECT client, available, held, locked, transactions FROM accounts
+                 ORDER BY client",
+            )?
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    Account {
+                        available: row.get::<_, Amount>(1)?.0,
+                        held: row.get::<_, Amount>(2)?.0,
+                        locked: row.get(3)?,
+                        transactions: row.get(4)?,
+                    },
+                ))
+            })?
+            .collect()
+    }
+
+    fn transaction(&self, tx: TransactionID) -> rusqlite::Result<Option<StoredTransaction>> {
+        self.connection
+            .prepare_cached(
+                "SELECT client, amount, state, sequence, timestamp FROM transactions
+                 WHERE tx = ?1",
+            )?
+            .query_row([tx], Self::read_transaction)
+            .optional()
+    }
+
+    fn insert_transaction(
+        &mut self,
+        tx: TransactionID,
+        transaction: StoredTransaction,
+    ) -> rusqlite::Result<()> {
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO transactions (tx, client, amount, state, sequence, timestamp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![
+                tx,
+                transaction.client,
+                Amount(transaction.amount),
+                transaction.state,
+                transaction.sequence,
+                transaction.timestamp
+            ])?;
+        Ok(())
+    }
+
+    fn transactions(&self) -> rusqlite::Result<Vec<(TransactionID, StoredTransaction)>> {
+        self.connection
+            .prepare_cached(
+                "SELECT tx, client, amount, state, sequence, timestamp FROM transactions
+                 ORDER BY tx",
+            )?
+            .query_map([], |row| Ok((row.get("tx")?, Self::read_transaction(row)?)))?
+            .collect()
+    }
+
+    fn evict(
+        &mut self,
+        expired: &mut dyn FnMut(TransactionID, &StoredTransaction) -> bool,
+    ) -> rusqlite::Result<usize> {
+        // Rows are read through a cursor, never all held at once, and expired ids are set aside
+        // in a table rather than deleted from under it.
+        let mut select = self.connection.prepare_cached(
+            "SELECT tx, client, amount, state, sequence, timestamp FROM transactions",
+        )?;
+        let mut set_aside = self
+            .connection
+            .prepare_cached("INSERT INTO expired (tx) VALUES (?1)")?;
+        let mut rows = select.query([])?;
+        let mut remaining = 0;
+        while let Some(row) = rows.next()? {
+            let tx = row.get("tx")?;
+            if expired(tx, &Self::read_transaction(row)?) {
+                set_aside.execute([tx])?;
+                self.evicted.insert(tx, tx);
+            } else {
+                remaining += 1;
+            }
+        }
+        drop(rows);
+
+        self.connection.execute_batch(
+            "DELETE FROM transactions WHERE tx IN (SELECT tx FROM expired);
+             DELETE FROM expired;",
+        )?;
+        Ok(remaining)
+    }
+
+    fn is_evicted(&self, tx: TransactionID) -> rusqlite::Result<bool> {
+        Ok(self.evicted.contains(tx))
+    }
+
+    fn evicted(&self) -> rusqlite::Result<Vec<(TransactionID, TransactionID)>> {
+        Ok(self.evicted.ranges().collect())
+    }
+
+    fn insert_evicted(
+        &mut self,
+        first: TransactionID,
+        last: TransactionID,
+    ) -> rusqlite::Result<()> {
+        self.evicted.insert(first, last);
+        Ok(())
+    }
+
+    fn latest_timestamp(&self) -> rusqlite::Result<Option<Timestamp>> {
+        Ok(self.latest_timestamp)
+    }
+
+    fn set_latest_timestamp(&mut self, timestamp: Timestamp) -> rusqlite::Result<()> {
+        self.latest_timestamp = Some(timestamp);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> rusqlite::Result<()> {
+        self.connection.execute("DELETE FROM evicted", [])?;
+        let mut insert = self
+            .connection
+            .prepare_cached("INSERT INTO evicted (first, last) VALUES (?1, ?2)")?;
+        for (first, last) in self.evicted.ranges() {
+            insert.execute([first, last])?;
+        }
+        self.connection.execute("DELETE FROM clock", [])?;
+        if let Some(timestamp) = self.latest_timestamp {
+            self.connection.execute(
+                "INSERT INTO clock (latest_timestamp) VALUES (?1)",
+                [timestamp],
+            )?;
+        }
+        self.connection.execute_batch("COMMIT; BEGIN")
+    }
+}
+
+/// A [`Decimal`] stored as text.
+struct Amount(Decimal);
+
+impl ToSql for Amount {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0.to_string()))
+    }
+}
+
+impl FromSql for Amount {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Decimal::from_str(value.as_str()?)
+            .map(Amount)
+            .map_err(|error| FromSqlError::Other(error.to_string().into()))
+    }
+}
+
+impl ToSql for Timestamp {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let millis = i64::try_from(self.millis())
+            .map_err(|error| rusqlite::Error::ToSqlConversionFailure(Box::new(error)))?;
+        Ok(ToSqlOutput::from(millis))
+    }
+}
+
+impl FromSql for Timestamp {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        u64::column_result(value).map(Timestamp::from_millis)
+    }
+}
+
+impl ToSql for TransactionState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(match self {
+            TransactionState::Declined => "declined",
+            TransactionState::Processed => "processed",
+            TransactionState::Disputed => "disputed",
+            TransactionState::Resolved => "resolved",
+            TransactionState::ChargedBack => "charged_back",
+        }))
+    }
+}
+
+impl FromSql for TransactionState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "declined" => Ok(TransactionState::Declined),
+            "processed" => Ok(TransactionState::Processed),
+            "disputed" => Ok(TransactionState::Disputed),
+            "resolved" => Ok(TransactionState::Resolved),
+            "charged_back" => Ok(TransactionState::ChargedBack),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, DisputeWindow, TimestampOrder};
+    use crate::engine::Engine;
+    use crate::rejection::Rejection;
+    use crate::sqlite::SqliteStore;
+    use crate::store::Store;
+    use crate::transaction::{Timestamp, Transaction};
+    use std::path::PathBuf;
+
+    struct TempDatabase(PathBuf);
+
+    impl TempDatabase {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("toy-engine-{name}-{}.sqlite", std::process::id()));
+            let database = Self(path);
+            database.remove();
+            database
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::deposit(2, 1, 1.5),
+            Transaction::deposit(1, 2, 2.0),
+            Transaction::withdrawal(1, 3, 5.0),
+            Transaction::dispute(1, 2),
+            Transaction::deposit(3, 4, 1.0),
+            Transaction::dispute(3, 4),
+            Transaction::chargeback(3, 4),
+        ]
+    }
+
+    fn apply<S: Store>(engine: &mut Engine<S>) {
+        for transaction in transactions() {
+            let _ = engine.try_handle_transaction(transaction).unwrap();
+        }
+    }
+
+    #[test]
+    fn matches_the_memory_store() {
+        let mut memory = Engine::default();
+        apply(&mut memory);
+        let mut sqlite =
+            Engine::with_store(Config::default(), SqliteStore::open_in_memory().unwrap());
+        apply(&mut sqlite);
+
+        assert_eq!(memory.snapshot(), sqlite.try_snapshot().unwrap());
+        assert_eq!(
+            memory.output().collect::<Vec<_>>(),
+            sqlite.try_output().unwrap().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rejections_match_the_memory_store() {
+        let mut engine =
+            Engine::with_store(Config::default(), SqliteStore::open_in_memory().unwrap());
+        apply(&mut engine);
+
+        assert_eq!(
+            Err(Rejection::DuplicateTransaction),
+            engine
+                .try_handle_transaction(Transaction::deposit(1, 3, 1.0))
+                .unwrap()
+        );
+        assert_eq!(
+            Err(Rejection::AccountLocked),
+            engine
+                .try_handle_transaction(Transaction::deposit(3, 5, 1.0))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn flushed_state_outlives_the_store() {
+        let database = TempDatabase::new("flushed");
+        let mut engine =
+            Engine::with_store(Config::default(), SqliteStore::open(&database.0).unwrap());
+        apply(&mut engine);
+        engine.store_mut().flush().unwrap();
+        let expected = engine.try_snapshot().unwrap();
+        drop(engine);
+
+        let mut reopened =
+            Engine::with_store(Config::default(), SqliteStore::open(&database.0).unwrap());
+        assert_eq!(expected, reopened.try_snapshot().unwrap());
+        assert_eq!(
+            Ok(()),
+            reopened
+                .try_handle_transaction(Transaction::resolve(1, 2))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn flushed_clock_outlives_the_store() {
+        let config = Config {
+            timestamp_order: TimestampOrder::Monotonic,
+            ..Config::default()
+        };
+        let database = TempDatabase::new("clock");
+        let mut engine = Engine::with_store(config, SqliteStore::open(&database.0).unwrap());
+        engine
+            .try_handle_transaction(Transaction::deposit(1, 1, 1.0).at(2000))
+            .unwrap()
+            .unwrap();
+        engine.store_mut().flush().unwrap();
+        drop(engine);
+
+        let mut reopened = Engine::with_store(config, SqliteStore::open(&database.0).unwrap());
+        assert_eq!(
+            Err(Rejection::OutOfOrder),
+            reopened
+                .try_handle_transaction(Transaction::deposit(1, 2, 1.0).at(1000))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn unflushed_writes_are_rolled_back() {
+        let database = TempDatabase::new("unflushed");
+        let mut engine =
+            Engine::with_store(Config::default(), SqliteStore::open(&database.0).unwrap());
+        apply(&mut engine);
+        drop(engine);
+
+        let reopened = SqliteStore::open(&database.0).unwrap();
+        assert!(reopened.accounts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn eviction_matches_the_memory_store() {
+        let config = Config {
+            dispute_window: DisputeWindow {
+                transactions: Some(0),
+                millis: None,
+            },
+            ..Config::default()
+        };
+        let mut memory = Engine::new(config);
+        apply(&mut memory);
+        memory.evict_expired();
+        let database = TempDatabase::new("evicted");
+        let mut sqlite = Engine::with_store(config, SqliteStore::open(&database.0).unwrap());
+        apply(&mut sqlite);
+        sqlite.try_evict_expired().unwrap();
+        sqlite.store_mut().flush().unwrap();
+        drop(sqlite);
+
+        let reopened = Engine::with_store(config, SqliteStore::open(&database.0).unwrap());
+        assert!(!memory.snapshot().evicted.is_empty());
+        assert_eq!(memory.snapshot(), reopened.try_snapshot().unwrap());
+    }
+
+    #[test]
+    fn repeated_evictions_only_delete_expired_rows() {
+        let config = Config {
+            dispute_window: DisputeWindow {
+                transactions: Some(2),
+                millis: None,
+            },
+            ..Config::default()
+        };
+        let mut engine = Engine::with_store(config, SqliteStore::open_in_memory().unwrap());
+        for tx in 1..=4 {
+            engine
+                .try_handle_transaction(Transaction::deposit(1, tx, 1.0))
+                .unwrap()
+                .unwrap();
+            engine.try_evict_expired().unwrap();
+        }
+
+        let stored: Vec<_> = engine
+            .store()
+            .transactions()
+            .unwrap()
+            .into_iter()
+            .map(|(tx, _)| tx)
+            .collect();
+        assert_eq!(vec![3, 4], stored);
+        assert_eq!(vec![(1, 2)], engine.store().evicted().unwrap());
+    }
+
+    #[test]
+    fn older_databases_are_migrated() {
+        let database = TempDatabase::new("migrated");
+        let connection = rusqlite::Connection::open(&database.0).unwrap();
+        connection.execute_batch(super::SCHEMA).unwrap();
+        connection
+            .execute_batch("INSERT INTO accounts VALUES (1, '1.5', '0', 0)")
+            .unwrap();
+        drop(connection);
+
+        let store = SqliteStore::open(&database.0).unwrap();
+        let (client, account) = store.accounts().unwrap()[0];
+        assert_eq!((1, 0), (client, account.transactions));
+        assert_eq!("1.5", account.available.to_string());
+        assert_eq!(None, store.latest_timestamp().unwrap());
+    }
+
+    #[test]
+    fn clock_is_migrated_from_applied_transactions() {
+        let database = TempDatabase::new("migrated-clock");
+        let connection = rusqlite::Connection::open(&database.0).unwrap();
+        connection.execute_batch(super::SCHEMA).unwrap();
+        connection.execute_batch(super::MIGRATIONS[0]).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+        connection
+            .execute_batch(
+                "INSERT INTO transactions VALUES (1, 1, '1', 'processed', 1, 2000);
+                 INSERT INTO transactions VALUES (2, 1, '1', 'declined', 0, 3000);",
+            )
+            .unwrap();
+        drop(connection);
+
+        let store = SqliteStore::open(&database.0).unwrap();
+        assert_eq!(
+            Some(Timestamp::from_millis(2000)),
+            store.latest_timestamp().unwrap()
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use crate::config::RedisputePolicy;
+use crate::rejection::Rejection;
+use crate::transaction::{ClientID, Timestamp};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// A deposit or withdrawal as remembered by the [`Engine`](crate::Engine).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StoredTransaction {
+    pub client: ClientID,
+    /// Positive for deposits, negative for withdrawals.
+    pub amount: Decimal,
+    pub state: TransactionState,
+    /// The account's [`transactions`](crate::Account::transactions) count once this was
+    /// applied, or zero if it was declined.
+    pub sequence: u64,
+    /// When the transaction happened, if the input said.
+    pub timestamp: Option<Timestamp>,
+}
+
+/// Where a stored transaction is in its dispute lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionState {
+    /// Rejected when it arrived, kept only so its id cannot be reused.
+    Declined,
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/// Something that moves a stored transaction through its dispute lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeEvent {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl TransactionState {
+    /// The state after `event`, or why `event` is not allowed from this state.
+    pub fn transition(
+        self,
+        event: DisputeEvent,
+        redispute_policy: RedisputePolicy,
+    ) -> Result<Self, Rejection> {
+        use DisputeEvent::*;
+        use TransactionState::*;
+
+        match (self, event) {
+            (Declined, _) => Err(Rejection::NotApplied),
+            (Processed, Dispute) => Ok(Disputed),
+            (Resolved, Dispute) if redispute_policy == RedisputePolicy::AfterResolve => {
+                Ok(Disputed)
+            }
+            (Disputed, Dispute) => Err(Rejection::AlreadyDisputed),
+            (Resolved | ChargedBack, Dispute) => Err(Rejection::DisputeClosed),
+            (Disputed, Resolve) => Ok(Resolved),
+            (Disputed, Chargeback) => Ok(ChargedBack),
+            (Processed | Resolved | ChargedBack, Resolve | Chargeback) => {
+                Err(Rejection::NotDisputed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RedisputePolicy;
+    use crate::rejection::Rejection;
+    use crate::state::{DisputeEvent, TransactionState};
+
+    fn transition(
+        state: TransactionState,
+        event: DisputeEvent,
+    ) -> Result<TransactionState, Rejection> {
+        state.transition(event, RedisputePolicy::default())
+    }
+
+    #[test]
+    fn processed_can_only_be_disputed() {
+        let state = TransactionState::Processed;
+
+        assert_eq!(
+            Ok(TransactionState::Disputed),
+            transition(state, DisputeEvent::Dispute)
+        );
+        assert_eq!(
+            Err(Rejection::NotDisputed),
+            transition(state, DisputeEvent::Resolve)
+        );
+        assert_eq!(
+            Err(Rejection::NotDisputed),
+            transition(state, DisputeEvent::Chargeback)
+        );
+    }
+
+    #[test]
+    fn disputed_can_be_resolved_or_charged_back() {
+        let state = TransactionState::Disputed;
+
+        assert_eq!(
+            Err(Rejection::AlreadyDisputed),
+            transition(state, DisputeEvent::Dispute)
+        );
+        assert_eq!(
+            Ok(TransactionState::Resolved),
+            transition(state, DisputeEvent::Resolve)
+        );
+        assert_eq!(
+            Ok(TransactionState::ChargedBack),
+            transition(state, DisputeEvent::Chargeback)
+        );
+    }
+
+    #[test]
+    fn settled_disputes_are_closed() {
+        for state in [TransactionState::Resolved, TransactionState::ChargedBack] {
+            assert_eq!(
+                Err(Rejection::DisputeClosed),
+                transition(state, DisputeEvent::Dispute)
+            );
+            assert_eq!(
+                Err(Rejection::NotDisputed),
+                transition(state, DisputeEvent::Resolve)
+            );
+            assert_eq!(
+                Err(Rejection::NotDisputed),
+                transition(state, DisputeEvent::Chargeback)
+            );
+        }
+    }
+
+    #[test]
+    fn resolved_can_be_disputed_again_when_allowed() {
+        assert_eq!(
+            Ok(TransactionState::Disputed),
+            TransactionState::Resolved
+                .transition(DisputeEvent::Dispute, RedisputePolicy::AfterResolve)
+        );
+        assert_eq!(
+            Err(Rejection::DisputeClosed),
+            TransactionState::ChargedBack
+                .transition(DisputeEvent::Dispute, RedisputePolicy::AfterResolve)
+        );
+    }
+
+    #[test]
+    fn declined_cannot_be_disputed() {
+        for event in [
+            DisputeEvent::Dispute,
+            DisputeEvent::Resolve,
+            DisputeEvent::Chargeback,
+        ] {
+            assert_eq!(
+                Err(Rejection::NotApplied),
+                transition(TransactionState::Declined, event)
+            );
+        }
+    }
+}
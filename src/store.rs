@@ -0,0 +1,254 @@
+use crate::account::Account;
+use crate::state::StoredTransaction;
+use crate::transaction::{ClientID, Timestamp, TransactionID};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+
+/// Where an [`Engine`](crate::Engine) keeps its accounts and stored transactions.
+pub trait Store {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn account(&self, client: ClientID) -> Result<Option<Account>, Self::Error>;
+
+    fn insert_account(&mut self, client: ClientID, account: Account) -> Result<(), Self::Error>;
+
+    /// Every account, ordered by client.
+    fn accounts(&self) -> Result<Vec<(ClientID, Account)>, Self::Error>;
+
+    fn transaction(&self, tx: TransactionID) -> Result<Option<StoredTransaction>, Self::Error>;
+
+    fn insert_transaction(
+        &mut self,
+        tx: TransactionID,
+        transaction: StoredTransaction,
+    ) -> Result<(), Self::Error>;
+
+    /// Every stored transaction, ordered by id.
+    fn transactions(&self) -> Result<Vec<(TransactionID, StoredTransaction)>, Self::Error>;
+
+    /// Removes every stored transaction that `expired` picks, returning how many remain. Each
+    /// removed id is remembered, so that it is still rejected as a duplicate.
+    fn evict(
+        &mut self,
+        expired: &mut dyn FnMut(TransactionID, &StoredTransaction) -> bool,
+    ) -> Result<usize, Self::Error>;
+
+    fn is_evicted(&self, tx: TransactionID) -> Result<bool, Self::Error>;
+
+    /// Every evicted id, as ordered and disjoint inclusive ranges.
+    fn evicted(&self) -> Result<Vec<(TransactionID, TransactionID)>, Self::Error>;
+
+    /// Remembers every id from `first` to `last` inclusive as evicted.
+    fn insert_evicted(
+        &mut self,
+        first: TransactionID,
+        last: TransactionID,
+    ) -> Result<(), Self::Error>;
+
+    /// The latest timestamp of an applied transaction, which the engine orders and expires
+    /// transactions by.
+    fn latest_timestamp(&self) -> Result<Option<Timestamp>, Self::Error>;
+
+    fn set_latest_timestamp(&mut self, timestamp: Timestamp) -> Result<(), Self::Error>;
+
+    /// Makes every insert so far durable, for stores that batch their writes.
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Keeps everything in memory, which is lost when the process exits.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    accounts: BTreeMap<ClientID, Account>,
+    transactions: HashMap<TransactionID, StoredTransaction>,
+    evicted: IdRanges,
+    latest_timestamp: Option<Timestamp>,
+}
+
+impl MemoryStore {
+    /// Borrows a client's account without copying it.
+    pub fn get_account(&self, client: ClientID) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// Borrows a stored transaction without copying it.
+    pub fn get_transaction(&self, tx: TransactionID) -> Option<&StoredTransaction> {
+        self.transactions.get(&tx)
+    }
+
+    /// The number of accounts.
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+}
+
+impl Store for MemoryStore {
+    type Error = Infallible;
+
+    fn account(&self, client: ClientID) -> Result<Option<Account>, Infallible> {
+        Ok(self.accounts.get(&client).copied())
+    }
+
+    fn insert_account(&mut self, client: ClientID, account: Account) -> Result<(), Infallible> {
+        self.accounts.insert(client, account);
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<(ClientID, Account)>, Infallible> {
+        Ok(self
+            .accounts
+            .iter()
+            .map(|(client, account)| (*client, *account))
+            .collect())
+    }
+
+    fn transaction(&self, tx: TransactionID) -> Result<Option<StoredTransaction>, Infallible> {
+        Ok(self.transactions.get(&tx).copied())
+    }
+
+    fn insert_transaction(
+        &mut self,
+        tx: TransactionID,
+        transaction: StoredTransaction,
+    ) -> Result<(), Infallible> {
+        self.transactions.insert(tx, transaction);
+        Ok(())
+    }
+
+    fn transactions(&self) -> Result<Vec<(TransactionID, StoredTransaction)>, Infallible> {
+        let mut transactions: Vec<_> = self
+            .transactions
+            .iter()
+            .map(|(tx, transaction)| (*tx, *transaction))
+            .collect();
+        transactions.sort_by_key(|(tx, _)| *tx);
+        Ok(transactions)
+    }
+
+    fn evict(
+        &mut self,
+        expired: &mut dyn FnMut(TransactionID, &StoredTransaction) -> bool,
+    ) -> Result<usize, Infallible> {
+        self.transactions.retain(|tx, transaction| {
+            let expired = expired(*tx, transaction);
+            if expired {
+                self.evicted.insert(*tx, *tx);
+            }
+            !expired
+        });
+        Ok(self.transactions.len())
+    }
+
+    fn is_evicted(&self, tx: TransactionID) -> Result<bool, Infallible> {
+        Ok(self.evicted.contains(tx))
+    }
+
+    fn evicted(&self) -> Result<Vec<(TransactionID, TransactionID)>, Infallible> {
+        Ok(self.evicted.ranges().collect())
+    }
+
+    fn insert_evicted(
+        &mut self,
+        first: TransactionID,
+        last: TransactionID,
+    ) -> Result<(), Infallible> {
+        self.evicted.insert(first, last);
+        Ok(())
+    }
+
+    fn latest_timestamp(&self) -> Result<Option<Timestamp>, Infallible> {
+        Ok(self.latest_timestamp)
+    }
+
+    fn set_latest_timestamp(&mut self, timestamp: Timestamp) -> Result<(), Infallible> {
+        self.latest_timestamp = Some(timestamp);
+        Ok(())
+    }
+}
+
+/// A set of transaction ids, kept as ranges so that a long run of evicted ids costs as little
+/// as one.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct IdRanges {
+    /// The first id of each range, mapped to its last.
+    ranges: BTreeMap<TransactionID, TransactionID>,
+}
+
+impl IdRanges {
+    /// Adds every id from `first` to `last` inclusive, merging any ranges it overlaps or
+    /// touches.
+    pub(crate) fn insert(&mut self, mut first: TransactionID, mut last: TransactionID) {
+        if let Some((&start, &end)) = self.ranges.range(..first).next_back()
+            && end.saturating_add(1) >= first
+        {
+            first = start;
+            last = last.max(end);
+        }
+        let absorbed: Vec<_> = self
+            .ranges
+            .range(first..=last.saturating_add(1))
+            .map(|(&start, &end)| (start, end))
+            .collect();
+        for (start, end) in absorbed {
+            self.ranges.remove(&start);
+            last = last.max(end);
+        }
+        self.ranges.insert(first, last);
+    }
+
+    pub(crate) fn contains(&self, tx: TransactionID) -> bool {
+        self.ranges
+            .range(..=tx)
+            .next_back()
+            .is_some_and(|(_, &last)| last >= tx)
+    }
+
+    pub(crate) fn ranges(&self) -> impl Iterator<Item = (TransactionID, TransactionID)> + '_ {
+        self.ranges.iter().map(|(&first, &last)| (first, last))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::IdRanges;
+    use crate::transaction::TransactionID;
+
+    #[test]
+    fn touching_ranges_are_merged() {
+        let mut ids = IdRanges::default();
+        ids.insert(5, 5);
+        ids.insert(7, 9);
+        ids.insert(6, 6);
+        ids.insert(20, 20);
+
+        assert_eq!(vec![(5, 9), (20, 20)], ids.ranges().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let mut ids = IdRanges::default();
+        ids.insert(10, 12);
+        ids.insert(1, 3);
+        ids.insert(2, 11);
+
+        assert_eq!(vec![(1, 12)], ids.ranges().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn contains_only_ids_in_a_range() {
+        let mut ids = IdRanges::default();
+        ids.insert(3, 4);
+        ids.insert(TransactionID::MAX, TransactionID::MAX);
+
+        assert!(!ids.contains(2));
+        assert!(ids.contains(3));
+        assert!(ids.contains(4));
+        assert!(!ids.contains(5));
+        assert!(ids.contains(TransactionID::MAX));
+    }
+}
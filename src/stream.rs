@@ -0,0 +1,508 @@
+use crate::engine::Engine;
+use crate::input::{InputFormat, LineParser};
+use crate::output::AccountOutput;
+use crate::rejection::Rejection;
+use crate::snapshot::Snapshot;
+use crate::store::{MemoryStore, Store};
+use crate::transaction::{ClientID, Transaction};
+use std::collections::HashMap;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+/// How many transactions can wait for the engine by default before streams feeding it wait too.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// A client's account as it changes, or `None` until it has one.
+pub type AccountWatch = watch::Receiver<Option<AccountOutput>>;
+
+type Query<S> = Box<dyn FnOnce(&Engine<S>) + Send>;
+
+enum Command<S> {
+    Apply(Transaction, Option<oneshot::Sender<Result<(), Rejection>>>),
+    Query(Query<S>),
+    Watch(ClientID, oneshot::Sender<AccountWatch>),
+    Stop,
+}
+
+/// Applies transactions from any number of concurrent streams to one [`Engine`], which runs on
+/// a blocking thread of the tokio runtime.
+///
+/// Every transaction and query joins one bounded queue, so each stream's transactions are
+/// applied in the order they were read from it, and a stream waits whenever the queue is full.
+pub struct AsyncEngine<S = MemoryStore> {
+    handle: EngineHandle<S>,
+    task: JoinHandle<anyhow::Result<Engine<S>>>,
+}
+
+/// A cheaply cloned way to feed an [`AsyncEngine`] and query it.
+pub struct EngineHandle<S = MemoryStore> {
+    sender: mpsc::Sender<Command<S>>,
+}
+
+/// The engine's verdict on a [queued](EngineHandle::queue) transaction.
+pub struct Verdict(oneshot::Receiver<Result<(), Rejection>>);
+
+/// How many rows were read from one stream.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamSummary {
+    /// Rows queued for the engine, whether or not it then rejects them.
+    pub transactions: u64,
+    /// Rows skipped as they could not be deserialized.
+    pub malformed: u64,
+}
+
+impl<S: Store + Send + 'static> AsyncEngine<S> {
+    /// Moves `engine` onto its own thread, with room for `capacity` transactions to queue for
+    /// it. Must be called from within a tokio runtime.
+    pub fn spawn(engine: Engine<S>, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        Self {
+            handle: EngineHandle { sender },
+            task: tokio::task::spawn_blocking(move || apply(engine, receiver)),
+        }
+    }
+
+    pub fn handle(&self) -> EngineHandle<S> {
+        self.handle.clone()
+    }
+
+    /// Stops the engine once everything queued so far is applied, and returns it.
+    ///
+    /// Fails with the store's error if one stopped the engine early.
+    pub async fn shutdown(self) -> anyhow::Result<Engine<S>> {
+        // If the engine has already stopped, the task says why.
+        let _ = self.handle.sender.send(Command::Stop).await;
+        self.task.await?
+    }
+}
+
+impl<S: Store + Send + 'static> EngineHandle<S> {
+    /// Applies a transaction, waiting for the engine's verdict.
+    pub async fn submit(&self, transaction: Transaction) -> anyhow::Result<Result<(), Rejection>> {
+        self.queue(transaction).await?.wait().await
+    }
+
+    /// Applies transactions in order, waiting for every verdict. They are all queued before
+    /// waiting, so a batch does not wait on the engine once per transaction.
+    pub async fn submit_batch(
+        &self,
+        transactions: impl IntoIterator<Item = Transaction>,
+    ) -> anyhow::Result<Vec<Result<(), Rejection>>> {
+        let mut verdicts = Vec::new();
+        for transaction in transactions {
+            verdicts.push(self.queue(transaction).await?);
+        }
+        let mut results = Vec::with_capacity(verdicts.len());
+        for verdict in verdicts {
+            results.push(verdict.wait().await?);
+        }
+        Ok(results)
+    }
+
+    /// Queues a transaction, waiting only for room in the queue, and returns the verdict to
+    /// wait for once it is applied.
+    pub async fn queue(&self, transaction: Transaction) -> anyhow::Result<Verdict> {
+        let (reply, verdict) = oneshot::channel();
+        self.send(Command::Apply(transaction, Some(reply))).await?;
+        Ok(Verdict(verdict))
+    }
+
+    /// Queues a transaction without waiting for it to be applied, only for room in the queue.
+    pub async fn send_transaction(&self, transaction: Transaction) -> anyhow::Result<()> {
+        self.send(Command::Apply(transaction, None)).await
+    }
+
+    /// Reads transactions in `format` a line at a time from `reader` and queues them in order,
+    /// skipping rows that cannot be deserialized. Anything queued once this returns, such as a
+    /// query, sees every transaction from the stream applied.
+    ///
+    /// Fails on a line longer than [`LineParser::MAX_LINE_LEN`], without reading any further.
+    pub async fn process(
+        &self,
+        format: InputFormat,
+        reader: impl AsyncRead + Unpin,
+    ) -> anyhow::Result<StreamSummary> {
+        let mut parser = LineParser::new(format)?;
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        let mut summary = StreamSummary::default();
+        loop {
+            line.clear();
+            let limit = LineParser::MAX_LINE_LEN as u64 + 1;
+            if (&mut reader).take(limit).read_line(&mut line).await? == 0 {
+                break;
+            }
+            anyhow::ensure!(
+                line.len() <= LineParser::MAX_LINE_LEN || line.ends_with('\n'),
+                "a line is longer than {} bytes",
+                LineParser::MAX_LINE_LEN
+            );
+            let Some(row) = parser.parse(&line) else {
+                continue;
+            };
+            match row.transaction {
+                Ok(transaction) => {
+                    self.send_transaction(transaction).await?;
+                    summary.transactions += 1;
+                }
+                Err(_) => summary.malformed += 1,
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Runs `query` against the engine once everything queued before it is applied.
+    pub async fn read<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Engine<S>) -> T + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let (reply, result) = oneshot::channel();
+        self.send(Command::Query(Box::new(move |engine| {
+            let _ = reply.send(query(engine));
+        })))
+        .await?;
+        result.await.map_err(|_| stopped())
+    }
+
+    /// Follows a client's account, which changes whenever a transaction is applied to it. A
+    /// receiver that falls behind skips to the latest state.
+    pub async fn watch(&self, client: ClientID) -> anyhow::Result<AccountWatch> {
+        let (reply, watch) = oneshot::channel();
+        self.send(Command::Watch(client, reply)).await?;
+        watch.await.map_err(|_| stopped())
+    }
+
+    /// Summarises every account for output, as [`Engine::try_output`] would.
+    pub async fn output(&self) -> anyhow::Result<Vec<AccountOutput>> {
+        Ok(self
+            .read(|engine| engine.try_output().map(Iterator::collect))
+            .await??)
+    }
+
+    /// Captures the accounts and stored transactions, as [`Engine::try_snapshot`] would.
+    pub async fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        Ok(self.read(|engine| engine.try_snapshot()).await??)
+    }
+
+    async fn send(&self, command: Command<S>) -> anyhow::Result<()> {
+        self.sender.send(command).await.map_err(|_| stopped())
+    }
+}
+
+impl Verdict {
+    /// Waits for the transaction to be applied, and returns whether it was rejected.
+    pub async fn wait(self) -> anyhow::Result<Result<(), Rejection>> {
+        self.0.await.map_err(|_| stopped())
+    }
+}
+
+impl<S> Clone for EngineHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+fn stopped() -> anyhow::Error {
+    anyhow::anyhow!("the engine has stopped")
+}
+
+fn apply<S: Store>(
+    mut engine: Engine<S>,
+    mut receiver: mpsc::Receiver<Command<S>>,
+) -> anyhow::Result<Engine<S>> {
+    let mut watchers: HashMap<ClientID, watch::Sender<Option<AccountOutput>>> = HashMap::new();
+    while let Some(command) = receiver.blocking_recv() {
+        match command {
+            Command::Apply(transaction, reply) => {
+                let client = transaction.client;
+                let verdict = engine.try_handle_transaction(transaction)?;
+                if verdict.is_ok()
+                    && let Some(watcher) = watchers.get(&client)
+                {
+                    if watcher.is_closed() {
+                        watchers.remove(&client);
+                    } else {
+                        watcher.send_replace(engine.try_account_output(client)?);
+                    }
+                }
+                if let Some(reply) = reply {
+                    // The submitter may have stopped waiting.
+                    let _ = reply.send(verdict);
+                }
+            }
+            Command::Query(query) => query(&engine),
+            Command::Watch(client, reply) => {
+                // Otherwise a client's abandoned watcher is only dropped once it transacts again.
+                watchers.retain(|_, watcher| !watcher.is_closed());
+                let account = engine.try_account_output(client)?;
+                let watcher = watchers
+                    .entry(client)
+                    .or_insert_with(|| watch::channel(None).0);
+                // Receivers already watching are only woken by a change to the account.
+                watcher.send_if_modified(|current| {
+                    let modified = *current != account;
+                    *current = account;
+                    modified
+                });
+                let _ = reply.send(watcher.subscribe());
+            }
+            Command::Stop => break,
+        }
+    }
+    Ok(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::input::{InputFormat, LineParser};
+    use crate::rejection::Rejection;
+    use crate::stream::{AccountWatch, AsyncEngine, DEFAULT_CAPACITY, StreamSummary};
+    use crate::transaction::Transaction;
+    use rust_decimal::Decimal;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// A stream of deposits and withdrawals for one client, with ids unique to that client.
+    fn stream(client: u16) -> String {
+        let mut csv = String::from("type,client,tx,amount\n");
+        for index in 0..100u32 {
+            let tx = u32::from(client) * 1000 + index;
+            let r#type = if index % 3 == 2 {
+                "withdrawal"
+            } else {
+                "deposit"
+            };
+            csv.push_str(&format!("{type},{client},{tx},{}.5\n", index % 7));
+        }
+        csv
+    }
+
+    #[test]
+    fn concurrent_streams_match_one_engine() {
+        let mut expected = Engine::default();
+        for client in 0..50 {
+            for row in InputFormat::Csv.rows(stream(client).as_bytes()).unwrap() {
+                let _ = expected.handle_transaction(row.unwrap().transaction.unwrap());
+            }
+        }
+
+        let output = block_on(async {
+            let engine = AsyncEngine::spawn(Engine::default(), 8);
+            let streams: Vec<_> = (0..50)
+                .map(|client| {
+                    let handle = engine.handle();
+                    tokio::spawn(async move {
+                        handle
+                            .process(InputFormat::Csv, stream(client).as_bytes())
+                            .await
+                    })
+                })
+                .collect();
+            for stream in streams {
+                let summary = stream.await.unwrap().unwrap();
+                assert_eq!(100, summary.transactions);
+            }
+            let output = engine.handle().output().await.unwrap();
+            engine.shutdown().await.unwrap();
+            output
+        });
+
+        assert_eq!(expected.output().collect::<Vec<_>>(), output);
+    }
+
+    #[test]
+    fn submit_returns_the_rejection() {
+        block_on(async {
+            let engine = AsyncEngine::spawn(Engine::default(), DEFAULT_CAPACITY);
+            let handle = engine.handle();
+
+            assert_eq!(
+                Ok(()),
+                handle
+                    .submit(Transaction::deposit(1, 1, 1.0))
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(
+                Err(Rejection::InsufficientFunds),
+                handle
+                    .submit(Transaction::withdrawal(1, 2, 2.0))
+                    .await
+                    .unwrap()
+            );
+
+            let engine = engine.shutdown().await.unwrap();
+            assert!(engine.transaction(1).is_some());
+            assert!(handle.submit(Transaction::dispute(1, 1)).await.is_err());
+        });
+    }
+
+    #[test]
+    fn batch_verdicts_keep_their_order() {
+        let verdicts = block_on(async {
+            let engine = AsyncEngine::spawn(Engine::default(), 1);
+            engine
+                .handle()
+                .submit_batch([
+                    Transaction::deposit(1, 1, 1.0),
+                    Transaction::deposit(1, 1, 1.0),
+                    Transaction::dispute(1, 1),
+                ])
+                .await
+                .unwrap()
+        });
+
+        assert_eq!(
+            vec![Ok(()), Err(Rejection::DuplicateTransaction), Ok(())],
+            verdicts
+        );
+    }
+
+    #[test]
+    fn watchers_see_their_client_change() {
+        block_on(async {
+            let engine = AsyncEngine::spawn(Engine::default(), DEFAULT_CAPACITY);
+            let handle = engine.handle();
+            let mut watch = handle.watch(1).await.unwrap();
+            assert_eq!(None, *watch.borrow_and_update());
+
+            handle
+                .submit_batch([
+                    Transaction::deposit(2, 1, 5.0),
+                    Transaction::deposit(1, 2, 1.0),
+                ])
+                .await
+                .unwrap();
+            watch.changed().await.unwrap();
+            let account = watch.borrow_and_update().clone().unwrap();
+            assert_eq!((1, Decimal::ONE), (account.client, account.total));
+
+            // Neither a rejection nor another client's transaction is a change.
+            handle
+                .submit_batch([
+                    Transaction::withdrawal(1, 3, 2.0),
+                    Transaction::deposit(2, 4, 1.0),
+                ])
+                .await
+                .unwrap();
+            assert!(!watch.has_changed().unwrap());
+        });
+    }
+
+    #[test]
+    fn new_watchers_do_not_wake_existing_ones() {
+        block_on(async {
+            let engine = AsyncEngine::spawn(Engine::default(), DEFAULT_CAPACITY);
+            let handle = engine.handle();
+            handle
+                .submit(Transaction::deposit(1, 1, 1.0))
+                .await
+                .unwrap()
+                .unwrap();
+
+            let mut first = handle.watch(1).await.unwrap();
+            let total = |watch: &AccountWatch| watch.borrow().as_ref().map(|account| account.total);
+            assert_eq!(Some(Decimal::ONE), total(&first));
+            first.mark_unchanged();
+
+            let second = handle.watch(1).await.unwrap();
+            assert_eq!(Some(Decimal::ONE), total(&second));
+            assert!(!first.has_changed().unwrap());
+        });
+    }
+
+    #[test]
+    fn malformed_rows_are_counted() {
+        let input =
+            "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1.0\"}\nlemon\n\n";
+        let summary = block_on(async {
+            let engine = AsyncEngine::spawn(Engine::default(), DEFAULT_CAPACITY);
+            engine
+                .handle()
+                .process(InputFormat::Ndjson, input.as_bytes())
+                .await
+                .unwrap()
+        });
+
+        assert_eq!(
+            StreamSummary {
+                transactions: 1,
+                malformed: 1
+            },
+            summary
+        );
+    }
+
+    #[test]
+    fn overlong_lines_end_the_stream() {
+        let input = format!(
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,{}",
+            "1".repeat(LineParser::MAX_LINE_LEN)
+        );
+        let (error, engine) = block_on(async {
+            let engine = AsyncEngine::spawn(Engine::default(), DEFAULT_CAPACITY);
+            let error = engine
+                .handle()
+                .process(InputFormat::Csv, input.as_bytes())
+                .await
+                .unwrap_err();
+            (error, engine.shutdown().await.unwrap())
+        });
+
+        assert_eq!(
+            format!("a line is longer than {} bytes", LineParser::MAX_LINE_LEN),
+            error.to_string()
+        );
+        assert!(engine.transaction(1).is_some());
+        assert!(engine.transaction(2).is_none());
+    }
+
+    #[test]
+    fn streams_wait_while_the_engine_is_busy() {
+        block_on(async {
+            let engine = AsyncEngine::spawn(Engine::default(), 1);
+            let (release, busy) = std::sync::mpsc::channel::<()>();
+            let query = tokio::spawn({
+                let handle = engine.handle();
+                async move { handle.read(move |_| busy.recv().unwrap()).await }
+            });
+
+            let sent = Arc::new(AtomicBool::new(false));
+            let stream = tokio::spawn({
+                let handle = engine.handle();
+                let sent = sent.clone();
+                async move {
+                    for tx in 1..=3 {
+                        handle
+                            .send_transaction(Transaction::deposit(1, tx, 1.0))
+                            .await
+                            .unwrap();
+                    }
+                    sent.store(true, Ordering::SeqCst);
+                }
+            });
+            for _ in 0..100 {
+                tokio::task::yield_now().await;
+            }
+            assert!(!sent.load(Ordering::SeqCst));
+
+            release.send(()).unwrap();
+            query.await.unwrap().unwrap();
+            stream.await.unwrap();
+            assert!(sent.load(Ordering::SeqCst));
+            let engine = engine.shutdown().await.unwrap();
+            assert_eq!(3, engine.account(1).unwrap().transactions);
+        });
+    }
+}
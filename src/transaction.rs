@@ -1,22 +1,148 @@
+use chrono::{DateTime, SecondsFormat};
 use rust_decimal::Decimal;
 #[cfg(test)]
 use rust_decimal::prelude::FromPrimitive;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
 
+/// Identifies a client and their account.
 pub type ClientID = u16;
+/// Globally identifies a deposit or withdrawal.
 pub type TransactionID = u32;
 
-#[derive(Debug, Deserialize)]
+/// A single row of input.
+///
+/// Deposits and withdrawals carry an `amount`, the dispute types refer back to an earlier
+/// transaction by its `tx` and carry none. Any type can carry a `timestamp`, which a
+/// [`DisputeWindow`](crate::DisputeWindow) can be measured in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub r#type: TransactionType,
     pub client: ClientID,
     pub tx: TransactionID,
-    pub amount: Option<Decimal>,
+    pub amount: Option<PositiveAmount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
 }
 
-#[derive(Debug, Deserialize)]
+/// An amount strictly greater than zero, so that deposits and withdrawals can only move funds
+/// in the direction their type implies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Decimal")]
+pub struct PositiveAmount(Decimal);
+
+/// The error when an amount is zero or negative.
+#[derive(Debug, Error)]
+#[error("amount must be greater than zero")]
+pub struct NonPositiveAmount;
+
+impl PositiveAmount {
+    /// The underlying amount.
+    pub fn get(self) -> Decimal {
+        self.0
+    }
+}
+
+impl TryFrom<Decimal> for PositiveAmount {
+    type Error = NonPositiveAmount;
+
+    fn try_from(amount: Decimal) -> Result<Self, Self::Error> {
+        if amount <= Decimal::ZERO {
+            return Err(NonPositiveAmount);
+        }
+        Ok(Self(amount))
+    }
+}
+
+/// When a transaction happened, in milliseconds since the Unix epoch.
+///
+/// Read from either a number of milliseconds or an RFC 3339 string such as
+/// `2024-01-31T09:30:00.250Z`, and written as a number of milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(transparent)]
+pub struct Timestamp(u64);
+
+/// The error when a timestamp is neither a number of milliseconds nor RFC 3339, or is before
+/// the Unix epoch.
+#[derive(Debug, Error)]
+#[error("expected milliseconds since the Unix epoch or an RFC 3339 date and time")]
+pub struct InvalidTimestamp;
+
+impl Timestamp {
+    pub fn from_millis(millis: u64) -> Self {
+        Self(millis)
+    }
+
+    pub fn millis(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = InvalidTimestamp;
+
+    fn from_str(timestamp: &str) -> Result<Self, InvalidTimestamp> {
+        if let Ok(millis) = timestamp.parse() {
+            return Ok(Self(millis));
+        }
+        let millis = DateTime::parse_from_rfc3339(timestamp)
+            .map_err(|_| InvalidTimestamp)?
+            .timestamp_millis();
+        u64::try_from(millis)
+            .map(Self)
+            .map_err(|_| InvalidTimestamp)
+    }
+}
+
+impl Display for Timestamp {
+    /// Formats as RFC 3339 in UTC, to the millisecond.
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match i64::try_from(self.0)
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+        {
+            Some(time) => formatter.write_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            None => write!(formatter, "{}", self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                formatter.write_str("milliseconds since the Unix epoch or an RFC 3339 string")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, millis: u64) -> Result<Timestamp, E> {
+                Ok(Timestamp(millis))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, millis: i64) -> Result<Timestamp, E> {
+                u64::try_from(millis)
+                    .map(Timestamp)
+                    .map_err(|_| E::custom(InvalidTimestamp))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, timestamp: &str) -> Result<Timestamp, E> {
+                timestamp.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// The kind of a [`Transaction`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
@@ -34,7 +160,8 @@ impl Transaction {
             r#type: TransactionType::Deposit,
             client,
             tx,
-            amount: Some(Decimal::from_f64(amount).unwrap()),
+            amount: Some(PositiveAmount::try_from(Decimal::from_f64(amount).unwrap()).unwrap()),
+            timestamp: None,
         }
     }
 
@@ -43,7 +170,8 @@ impl Transaction {
             r#type: TransactionType::Withdrawal,
             client,
             tx,
-            amount: Some(Decimal::from_f64(amount).unwrap()),
+            amount: Some(PositiveAmount::try_from(Decimal::from_f64(amount).unwrap()).unwrap()),
+            timestamp: None,
         }
     }
 
@@ -53,6 +181,7 @@ impl Transaction {
             client,
             tx,
             amount: None,
+            timestamp: None,
         }
     }
 
@@ -62,6 +191,15 @@ impl Transaction {
             client,
             tx,
             amount: None,
+            timestamp: None,
+        }
+    }
+
+    /// The same transaction, timestamped.
+    pub fn at(self, millis: u64) -> Self {
+        Self {
+            timestamp: Some(Timestamp(millis)),
+            ..self
         }
     }
 
@@ -71,13 +209,14 @@ impl Transaction {
             client,
             tx,
             amount: None,
+            timestamp: None,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::transaction::Transaction;
+    use crate::transaction::{Timestamp, Transaction};
     use anyhow::Context;
 
     fn try_deserialize(csv: &str) -> anyhow::Result<Transaction> {
@@ -131,6 +270,45 @@ deposit, 1, -10, 2.5000
         );
     }
 
+    #[test]
+    fn negative_deposit() {
+        let input = "\
+type,client,tx,amount
+deposit, 1, 10, -2.5000
+";
+
+        assert_eq!(
+            "failed to deserialize transaction",
+            try_deserialize(input).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn zero_deposit() {
+        let input = "\
+type,client,tx,amount
+deposit, 1, 10, 0
+";
+
+        assert_eq!(
+            "failed to deserialize transaction",
+            try_deserialize(input).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn negative_withdrawal() {
+        let input = "\
+type,client,tx,amount
+withdrawal, 1, 10, -2.5000
+";
+
+        assert_eq!(
+            "failed to deserialize transaction",
+            try_deserialize(input).unwrap_err().to_string()
+        );
+    }
+
     #[test]
     fn deposit() {
         let input = "\
@@ -157,6 +335,53 @@ withdrawal,2,20,1.2345
         );
     }
 
+    #[test]
+    fn timestamped_deposit() {
+        let input = "\
+type,client,tx,amount,timestamp
+deposit, 1, 10, 2.5000, 1700000000000
+";
+
+        assert_eq!(
+            Transaction::deposit(1, 10, 2.5).at(1_700_000_000_000),
+            try_deserialize(input).unwrap()
+        );
+    }
+
+    #[test]
+    fn rfc3339_timestamp() {
+        let input = "\
+type,client,tx,amount,timestamp
+deposit, 1, 10, 2.5000, 2023-11-15T00:13:20+01:00
+";
+
+        assert_eq!(
+            Transaction::deposit(1, 10, 2.5).at(1_700_003_600_000),
+            try_deserialize(input).unwrap()
+        );
+    }
+
+    #[test]
+    fn timestamp_before_the_epoch() {
+        let input = "\
+type,client,tx,amount,timestamp
+deposit, 1, 10, 2.5000, 1969-12-31T23:59:59Z
+";
+
+        assert_eq!(
+            "failed to deserialize transaction",
+            try_deserialize(input).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn timestamp_displays_as_rfc3339() {
+        assert_eq!(
+            "2023-11-14T22:13:20.000Z",
+            Timestamp::from_millis(1_700_000_000_000).to_string()
+        );
+    }
+
     #[test]
     fn dispute() {
         let input = "\
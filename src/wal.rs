@@ -0,0 +1,343 @@
+use crate::engine::Engine;
+use crate::store::Store;
+use crate::transaction::Transaction;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Bytes before each record's payload: its length, checksum and sequence number.
+const HEADER_LEN: usize = 16;
+/// No transaction serializes anywhere near this long, so a longer length can only be corrupt.
+const MAX_PAYLOAD_LEN: u32 = 64 * 1024;
+
+/// An append-only log of every transaction handed to an [`Engine`], written before it is
+/// applied, so that the engine's exact state can be rebuilt after a crash.
+///
+/// Transactions the engine rejects are logged too, since a rejected deposit or withdrawal
+/// still reserves its id. Replaying the log therefore needs the same [`Config`](crate::Config)
+/// it was written under.
+///
+/// Each record is framed as its payload length, a CRC-32 of the rest of the record, a sequence
+/// number and the transaction as JSON, all little-endian. A record that is cut short or fails
+/// its checksum marks where the process stopped writing, so it and anything after it is
+/// truncated on recovery.
+pub struct WriteAheadLog {
+    file: BufWriter<File>,
+    sequence: u64,
+    unsynced: usize,
+    sync_every: usize,
+}
+
+/// What [`replay`] found in a log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Replay {
+    /// The sequence number of the last complete record, or the starting sequence if there
+    /// were none after it.
+    pub sequence: u64,
+    /// The number of records applied to the engine.
+    pub applied: u64,
+    /// The length of the log up to the end of the last complete record.
+    pub valid_len: u64,
+}
+
+impl WriteAheadLog {
+    /// Opens the log at `path`, creating it if needed, and replays every record after
+    /// `sequence` into `engine`. A torn final record is truncated away.
+    ///
+    /// Appended records are only made durable once `sync_every` have been written since the
+    /// last sync, or on [`sync`](Self::sync).
+    pub fn recover<S: Store>(
+        path: &Path,
+        engine: &mut Engine<S>,
+        sequence: u64,
+        sync_every: usize,
+    ) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let replay = replay(BufReader::new(&mut file), engine, sequence)?;
+        if file.metadata()?.len() > replay.valid_len {
+            file.set_len(replay.valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(replay.valid_len))?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            sequence: replay.sequence,
+            unsynced: 0,
+            sync_every: sync_every.max(1),
+        })
+    }
+
+    /// The sequence number of the last record written.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Appends `transaction`, syncing to disk if a batch is complete.
+    pub fn append(&mut self, transaction: &Transaction) -> anyhow::Result<()> {
+        let sequence = self.sequence + 1;
+        let payload = serde_json::to_vec(transaction)?;
+        let length = u32::try_from(payload.len())?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&sequence.to_le_bytes());
+        hasher.update(&payload);
+
+        self.file.write_all(&length.to_le_bytes())?;
+        self.file.write_all(&hasher.finalize().to_le_bytes())?;
+        self.file.write_all(&sequence.to_le_bytes())?;
+        self.file.write_all(&payload)?;
+        self.sequence = sequence;
+
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Makes every appended record durable.
+    pub fn sync(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Empties the log once its records are captured in a snapshot. Sequence numbers carry on
+    /// from where they were, so the snapshot can tell which records it already contains.
+    pub fn checkpoint(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.sync_all()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// Applies every complete record in `reader` with a sequence number after `sequence` to
+/// `engine`, stopping at the first torn or corrupt record.
+///
+/// Records at or before `sequence` are skipped, as they are already part of the snapshot the
+/// engine was restored from.
+pub fn replay<S: Store>(
+    mut reader: impl Read,
+    engine: &mut Engine<S>,
+    sequence: u64,
+) -> anyhow::Result<Replay> {
+    let mut replay = Replay {
+        sequence,
+        applied: 0,
+        valid_len: 0,
+    };
+
+    while let Some((record_sequence, transaction, len)) = next_record(&mut reader)? {
+        if record_sequence > replay.sequence {
+            // Rejections were already reported when the transaction first arrived.
+            let _ = engine.try_handle_transaction(transaction)?;
+            replay.sequence = record_sequence;
+            replay.applied += 1;
+        }
+        replay.valid_len += len;
+    }
+    Ok(replay)
+}
+
+/// Reads one record, returning `None` at the end of the log or at a torn or corrupt record.
+fn next_record(reader: &mut impl Read) -> anyhow::Result<Option<(u64, Transaction, u64)>> {
+    let mut header = [0; HEADER_LEN];
+    if !read_all(reader, &mut header)? {
+        return Ok(None);
+    }
+    let length = u32::from_le_bytes(header[0..4].try_into()?);
+    let checksum = u32::from_le_bytes(header[4..8].try_into()?);
+    let sequence = u64::from_le_bytes(header[8..16].try_into()?);
+    if length > MAX_PAYLOAD_LEN {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0; length as usize];
+    if !read_all(reader, &mut payload)? {
+        return Ok(None);
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[8..16]);
+    hasher.update(&payload);
+    if hasher.finalize() != checksum {
+        return Ok(None);
+    }
+    let Ok(transaction) = serde_json::from_slice(&payload) else {
+        return Ok(None);
+    };
+
+    Ok(Some((
+        sequence,
+        transaction,
+        (HEADER_LEN + payload.len()) as u64,
+    )))
+}
+
+/// Fills `buffer`, returning false if the reader ends first.
+fn read_all(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<bool> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::transaction::Transaction;
+    use crate::wal::{Replay, WriteAheadLog, replay};
+    use std::path::PathBuf;
+
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("toy-engine-wal-{name}-{}.log", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::deposit(1, 1, 2.0),
+            Transaction::deposit(2, 2, 1.0),
+            Transaction::withdrawal(1, 3, 5.0),
+            Transaction::dispute(1, 1),
+            Transaction::withdrawal(2, 4, 0.5),
+            Transaction::resolve(1, 1),
+        ]
+    }
+
+    /// Writes every transaction to a log, returning its bytes and where each record ends.
+    fn written_log(name: &str) -> (Vec<u8>, Vec<usize>) {
+        let log = TempLog::new(name);
+        let mut wal = WriteAheadLog::recover(&log.0, &mut Engine::default(), 0, 1).unwrap();
+        let mut ends = Vec::new();
+        for transaction in transactions() {
+            wal.append(&transaction).unwrap();
+            ends.push(std::fs::metadata(&log.0).unwrap().len() as usize);
+        }
+        (std::fs::read(&log.0).unwrap(), ends)
+    }
+
+    /// The engine after applying the first `count` transactions directly.
+    fn applied(count: usize) -> Engine {
+        let mut engine = Engine::default();
+        for transaction in transactions().into_iter().take(count) {
+            let _ = engine.handle_transaction(transaction);
+        }
+        engine
+    }
+
+    #[test]
+    fn replay_rebuilds_the_engine() {
+        let (log, ends) = written_log("replay");
+        let mut engine = Engine::default();
+
+        assert_eq!(
+            Replay {
+                sequence: 6,
+                applied: 6,
+                valid_len: *ends.last().unwrap() as u64,
+            },
+            replay(&log[..], &mut engine, 0).unwrap()
+        );
+        assert_eq!(applied(6).snapshot(), engine.snapshot());
+    }
+
+    #[test]
+    fn records_in_the_snapshot_are_skipped() {
+        let (log, _) = written_log("skipped");
+        let mut engine = applied(4);
+
+        let replay = replay(&log[..], &mut engine, 4).unwrap();
+        assert_eq!(2, replay.applied);
+        assert_eq!(applied(6).snapshot(), engine.snapshot());
+    }
+
+    #[test]
+    fn crash_at_any_offset_recovers_every_complete_record() {
+        let (log, ends) = written_log("crash");
+
+        for offset in 0..=log.len() {
+            let complete = ends.iter().filter(|&&end| end <= offset).count();
+            let mut engine = Engine::default();
+
+            let replay = replay(&log[..offset], &mut engine, 0).unwrap();
+            assert_eq!(complete as u64, replay.applied, "offset {offset}");
+            assert_eq!(applied(complete).snapshot(), engine.snapshot());
+        }
+    }
+
+    #[test]
+    fn corrupt_record_ends_the_log() {
+        let (mut log, ends) = written_log("corrupt");
+        log[ends[2] + 20] ^= 0xff;
+        let mut engine = Engine::default();
+
+        let replay = replay(&log[..], &mut engine, 0).unwrap();
+        assert_eq!(3, replay.applied);
+        assert_eq!(ends[2] as u64, replay.valid_len);
+        assert_eq!(applied(3).snapshot(), engine.snapshot());
+    }
+
+    #[test]
+    fn recover_truncates_a_torn_record_and_carries_on() {
+        let (log, ends) = written_log("torn-source");
+        let path = TempLog::new("torn");
+        std::fs::write(&path.0, &log[..ends[3] + 5]).unwrap();
+
+        let mut engine = Engine::default();
+        let mut wal = WriteAheadLog::recover(&path.0, &mut engine, 0, 10).unwrap();
+        assert_eq!(4, wal.sequence());
+        assert_eq!(ends[3] as u64, std::fs::metadata(&path.0).unwrap().len());
+
+        for transaction in transactions().into_iter().skip(4) {
+            wal.append(&transaction).unwrap();
+        }
+        wal.sync().unwrap();
+
+        let mut recovered = Engine::default();
+        WriteAheadLog::recover(&path.0, &mut recovered, 0, 10).unwrap();
+        assert_eq!(applied(6).snapshot(), recovered.snapshot());
+    }
+
+    #[test]
+    fn checkpoint_empties_the_log_and_keeps_the_sequence() {
+        let path = TempLog::new("checkpoint");
+        let mut wal = WriteAheadLog::recover(&path.0, &mut Engine::default(), 0, 1).unwrap();
+        for transaction in transactions().into_iter().take(2) {
+            wal.append(&transaction).unwrap();
+        }
+
+        wal.checkpoint().unwrap();
+        assert_eq!(0, std::fs::metadata(&path.0).unwrap().len());
+
+        wal.append(&Transaction::deposit(3, 5, 1.0)).unwrap();
+        let mut engine = applied(2);
+        let wal = WriteAheadLog::recover(&path.0, &mut engine, 2, 1).unwrap();
+        assert_eq!(3, wal.sequence());
+        assert!(engine.account(3).is_some());
+    }
+}
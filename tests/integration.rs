@@ -1,4 +1,6 @@
-use std::process::{Command, Output, Stdio};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Output, Stdio};
 
 fn call_toy_engine(args: &[&str]) -> Output {
     Command::new("./target/release/toy-engine")
@@ -9,6 +11,19 @@ fn call_toy_engine(args: &[&str]) -> Output {
         .unwrap()
 }
 
+fn call_toy_engine_with_stdin(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new("./target/release/toy-engine")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
 #[test]
 fn missing_argument() {
     let output = call_toy_engine(&[]);
@@ -26,7 +41,575 @@ fn ignores_invalid_orders() {
 
     assert!(output.status.success());
     assert_eq!(
-        "client,available,held,total,locked\n1,2.0005,0,2.0005,false\n",
+        "client,available,held,total,locked\n1,2.0005,0.0000,2.0005,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn missing_file() {
+    let output = call_toy_engine(&["tests/data/missing.csv"]);
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice())
+            .starts_with("Error: failed to open tests/data/missing.csv")
+    );
+}
+
+#[test]
+fn reads_from_stdin() {
+    let input = std::fs::read("tests/data/example.csv").unwrap();
+    let output = call_toy_engine_with_stdin(&["-"], &input);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n2,2.0000,0.0000,2.0000,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn multiple_files_are_one_stream() {
+    let output = call_toy_engine(&["tests/data/example.csv", "tests/data/example_continued.csv"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,-0.5000,2.0000,1.5000,false\n2,3.0000,0.0000,3.0000,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn stdin_between_files() {
+    let input = std::fs::read("tests/data/example_continued.csv").unwrap();
+    let output = call_toy_engine_with_stdin(&["tests/data/example.csv", "-"], &input);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,-0.5000,2.0000,1.5000,false\n2,3.0000,0.0000,3.0000,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn stdin_only_once() {
+    let output = call_toy_engine_with_stdin(&["-", "-"], b"");
+
+    assert!(!output.status.success());
+    assert_eq!(
+        "Error: stdin (`-`) can only be read once\n",
+        String::from_utf8_lossy(output.stderr.as_slice())
+    );
+}
+
+#[test]
+fn reports_rejected_rows() {
+    let report =
+        std::env::temp_dir().join(format!("toy-engine-rejections-{}.csv", std::process::id()));
+    let output = call_toy_engine(&[
+        "tests/data/some_invalid.csv",
+        "--rejections",
+        report.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,2.0005,0.0000,2.0005,false\n",
         String::from_utf8_lossy(output.stdout.as_slice())
     );
+    assert_eq!(
+        "input,line,record,reason,timestamp\n\
+tests/data/some_invalid.csv,3,\"cheese, -1, -1, 1.1\",invalid_field,\n\
+tests/data/some_invalid.csv,5,lemon,wrong_field_count,\n\
+tests/data/some_invalid.csv,7,\"deposit,\",wrong_field_count,\n",
+        std::fs::read_to_string(&report).unwrap()
+    );
+    std::fs::remove_file(report).unwrap();
+}
+
+#[test]
+fn strict_fails_on_first_invalid_row() {
+    let output = call_toy_engine(&["--strict", "tests/data/some_invalid.csv"]);
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice()).starts_with(
+            "Error: tests/data/some_invalid.csv, line 3: invalid `type`: unknown variant `cheese`"
+        )
+    );
+}
+
+#[test]
+fn strict_fails_on_missing_amount() {
+    let input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,\n";
+    let output = call_toy_engine_with_stdin(&["--strict", "-"], input);
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        "Error: -, line 3: deposits and withdrawals require an amount, disputes must not have one\n",
+        String::from_utf8_lossy(output.stderr.as_slice())
+    );
+}
+
+#[test]
+fn strict_accepts_valid_input() {
+    let output = call_toy_engine(&["--strict", "tests/data/example.csv"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n2,2.0000,0.0000,2.0000,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn input_format_from_extension() {
+    for path in ["tests/data/example.ndjson", "tests/data/example.json"] {
+        let output = call_toy_engine(&[path]);
+
+        assert!(output.status.success());
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n2,2.0000,0.0000,2.0000,false\n",
+            String::from_utf8_lossy(output.stdout.as_slice())
+        );
+    }
+}
+
+#[test]
+fn input_format_from_flag() {
+    let input = std::fs::read("tests/data/example.ndjson").unwrap();
+    let output = call_toy_engine_with_stdin(&["--input-format", "ndjson", "-"], &input);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n2,2.0000,0.0000,2.0000,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn invalid_input_format() {
+    let output = call_toy_engine(&["--input-format", "xml", "tests/data/example.csv"]);
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice())
+            .contains("expected one of csv, ndjson or json")
+    );
+}
+
+#[test]
+fn json_output_format() {
+    let output = call_toy_engine(&["--output-format", "json", "tests/data/example.csv"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "[{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false},\
+{\"client\":2,\"available\":\"2.0000\",\"held\":\"0.0000\",\"total\":\"2.0000\",\"locked\":false}]\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn table_output_format() {
+    let output = call_toy_engine(&["--output-format", "table", "tests/data/example.csv"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client  available    held   total  locked\n     1     1.5000  0.0000  1.5000   false\n     2     2.0000  0.0000  2.0000   false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn invalid_output_format() {
+    let output = call_toy_engine(&["--output-format", "xml", "tests/data/example.csv"]);
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice())
+            .contains("expected one of csv, json, ndjson or table")
+    );
+}
+
+#[test]
+fn total_output_order() {
+    let input = b"type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\ndeposit,3,3,3.0\n";
+    let output = call_toy_engine_with_stdin(&["--order", "total", "-"], input);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n2,1.0000,0.0000,1.0000,false\n3,3.0000,0.0000,3.0000,false\n1,5.0000,0.0000,5.0000,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn invalid_output_order() {
+    let output = call_toy_engine(&["--order", "balance", "tests/data/example.csv"]);
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice())
+            .contains("expected one of client, total or locked-first")
+    );
+}
+
+#[test]
+fn excess_precision_is_rejected_at_decimal_places() {
+    let input = b"type,client,tx,amount\ndeposit,1,1,1.005\ndeposit,1,2,0.5\n";
+    let output = call_toy_engine_with_stdin(&["--decimal-places", "2", "-"], input);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,0.50,0.00,0.50,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn excess_precision_is_rounded() {
+    let input = b"type,client,tx,amount\ndeposit,1,1,1.005\ndeposit,1,2,0.015\n";
+    let output = call_toy_engine_with_stdin(
+        &["--decimal-places", "2", "--round", "half-even", "-"],
+        input,
+    );
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,1.02,0.00,1.02,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn invalid_rounding_strategy() {
+    let output = call_toy_engine(&["--round", "sideways", "tests/data/example.csv"]);
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice())
+            .contains("expected one of half-even, half-up, half-down, down or up")
+    );
+}
+
+#[test]
+fn state_carries_over_between_runs() {
+    let state = std::env::temp_dir().join(format!("toy-engine-state-{}.json", std::process::id()));
+    let state = state.to_str().unwrap();
+
+    let monday = call_toy_engine_with_stdin(
+        &["--state-out", state, "-"],
+        b"type,client,tx,amount\ndeposit,1,1,2.0\n",
+    );
+    assert!(monday.status.success());
+
+    let tuesday = call_toy_engine_with_stdin(
+        &["--state-in", state, "--state-out", state, "-"],
+        b"type,client,tx,amount\ndeposit,1,2,1.0\ndispute,1,1,\n",
+    );
+    assert!(tuesday.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,1.0000,2.0000,3.0000,false\n",
+        String::from_utf8_lossy(tuesday.stdout.as_slice())
+    );
+
+    let wednesday = call_toy_engine_with_stdin(
+        &["--state-in", state, "-"],
+        b"type,client,tx,amount\nchargeback,1,1,\n",
+    );
+    assert!(wednesday.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,true\n",
+        String::from_utf8_lossy(wednesday.stdout.as_slice())
+    );
+    std::fs::remove_file(state).unwrap();
+}
+
+#[test]
+fn missing_state_in() {
+    let output = call_toy_engine(&["--state-in", "missing.json", "tests/data/example.csv"]);
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice())
+            .contains("failed to load state from missing.json")
+    );
+}
+
+#[test]
+fn wal_is_replayed_after_a_crash() {
+    let wal = std::env::temp_dir().join(format!("toy-engine-wal-{}.log", std::process::id()));
+    let wal = wal.to_str().unwrap();
+    let _ = std::fs::remove_file(wal);
+
+    let first = call_toy_engine_with_stdin(
+        &["--wal", wal, "-"],
+        b"type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,2,1.0\n",
+    );
+    assert!(first.status.success());
+
+    // Tear the last record in half, as if the process died while writing it.
+    let log = std::fs::read(wal).unwrap();
+    std::fs::write(wal, &log[..log.len() - 10]).unwrap();
+
+    let second = call_toy_engine_with_stdin(
+        &["--wal", wal, "-"],
+        b"type,client,tx,amount\ndispute,1,1,\n",
+    );
+    assert!(second.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,0.0000,2.0000,2.0000,false\n",
+        String::from_utf8_lossy(second.stdout.as_slice())
+    );
+    std::fs::remove_file(wal).unwrap();
+}
+
+#[test]
+fn state_out_empties_the_wal() {
+    let wal =
+        std::env::temp_dir().join(format!("toy-engine-checkpoint-{}.log", std::process::id()));
+    let state =
+        std::env::temp_dir().join(format!("toy-engine-checkpoint-{}.json", std::process::id()));
+    let (wal, state) = (wal.to_str().unwrap(), state.to_str().unwrap());
+    let _ = std::fs::remove_file(wal);
+
+    let first = call_toy_engine_with_stdin(
+        &["--wal", wal, "--state-out", state, "-"],
+        b"type,client,tx,amount\ndeposit,1,1,2.0\n",
+    );
+    assert!(first.status.success());
+    assert_eq!(0, std::fs::metadata(wal).unwrap().len());
+
+    let second = call_toy_engine_with_stdin(
+        &["--wal", wal, "--state-in", state, "-"],
+        b"type,client,tx,amount\ndeposit,1,2,1.0\n",
+    );
+    assert!(second.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n",
+        String::from_utf8_lossy(second.stdout.as_slice())
+    );
+    std::fs::remove_file(wal).unwrap();
+    std::fs::remove_file(state).unwrap();
+}
+
+#[test]
+fn transaction_history_spills_to_disk() {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=1000 {
+        input.push_str(&format!("deposit,1,{tx},1.0\n"));
+    }
+    input.push_str("dispute,1,1,\ndeposit,1,2,1.0\n");
+
+    let output = call_toy_engine_with_stdin(&["--transaction-memory", "1K", "-"], input.as_bytes());
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,999.0000,1.0000,1000.0000,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+}
+
+#[test]
+fn invalid_transaction_memory() {
+    let output = call_toy_engine(&["--transaction-memory", "lots", "tests/data/example.csv"]);
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice())
+            .contains("expected a size such as 4096, 64K, 512M or 2G")
+    );
+}
+
+#[test]
+fn disputes_outside_the_window_are_rejected() {
+    let report = std::env::temp_dir().join(format!(
+        "toy-engine-window-rejections-{}.csv",
+        std::process::id()
+    ));
+    let output = call_toy_engine(&[
+        "--dispute-window",
+        "2d",
+        "--rejections",
+        report.to_str().unwrap(),
+        "tests/data/timestamped.csv",
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,1.0000,2.0000,3.0000,false\n",
+        String::from_utf8_lossy(output.stdout.as_slice())
+    );
+    assert_eq!(
+        "input,line,record,reason,timestamp\n\
+tests/data/timestamped.csv,4,\"dispute,1,1,,1700172800001\",dispute_expired,2023-11-16T22:13:20.001Z\n",
+        std::fs::read_to_string(&report).unwrap()
+    );
+    std::fs::remove_file(report).unwrap();
+}
+
+#[test]
+fn invalid_dispute_window() {
+    let output = call_toy_engine(&["--dispute-window", "soon", "tests/data/example.csv"]);
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(output.stderr.as_slice())
+            .contains("expected a duration such as 500ms, 30s, 15m, 12h or 90d")
+    );
+}
+
+#[test]
+fn out_of_order_feed_is_reordered() {
+    let input = b"type,client,tx,amount,timestamp\n\
+deposit,1,1,1.0,2024-01-31T09:30:00Z\n\
+withdrawal,1,3,1.5,2024-01-31T09:30:02Z\n\
+deposit,1,2,1.0,2024-01-31T09:30:01Z\n\
+deposit,1,4,1.0,2024-01-31T09:30:10Z\n\
+deposit,1,5,1.0,2024-01-31T09:29:00Z\n";
+
+    let unordered = call_toy_engine_with_stdin(&["-"], input);
+    assert_eq!(
+        "client,available,held,total,locked\n1,4.0000,0.0000,4.0000,false\n",
+        String::from_utf8_lossy(unordered.stdout.as_slice())
+    );
+
+    // The withdrawal now follows both deposits, and the last deposit is too late to reorder.
+    let reordered = call_toy_engine_with_stdin(&["--reorder-window", "5s", "-"], input);
+    assert!(reordered.status.success());
+    assert_eq!(
+        "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n",
+        String::from_utf8_lossy(reordered.stdout.as_slice())
+    );
+}
+
+#[test]
+fn threads_match_one_thread() {
+    let inputs = [
+        "tests/data/example.csv",
+        "tests/data/example_continued.csv",
+        "tests/data/some_invalid.csv",
+        "tests/data/example.ndjson",
+    ];
+    let single = call_toy_engine(&inputs);
+    let threaded = call_toy_engine(&[&["--threads", "3"], &inputs[..]].concat());
+
+    assert!(threaded.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(single.stdout.as_slice()),
+        String::from_utf8_lossy(threaded.stdout.as_slice())
+    );
+}
+
+/// Starts a server subcommand on a free port, returning it and the address it listens on.
+fn start_server(command: &str) -> (Child, String) {
+    let mut server = Command::new("./target/release/toy-engine")
+        .args([command, "--listen", "127.0.0.1:0"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut banner = String::new();
+    BufReader::new(server.stderr.take().unwrap())
+        .read_line(&mut banner)
+        .unwrap();
+    let address = banner.trim().strip_prefix("listening on ").unwrap();
+    (server, address.to_string())
+}
+
+#[test]
+fn serve_answers_each_line() {
+    let (mut server, address) = start_server("serve");
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .write_all(b"deposit,1,1,2.0\nwithdrawal,1,2,3.0\nSNAPSHOT\n")
+        .unwrap();
+    let replies: Vec<_> = BufReader::new(stream)
+        .lines()
+        .take(5)
+        .map(Result::unwrap)
+        .collect();
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert_eq!(
+        vec![
+            "ok",
+            "insufficient_funds",
+            "client,available,held,total,locked",
+            "1,2.0000,0.0000,2.0000,false",
+            "",
+        ],
+        replies
+    );
+}
+
+#[test]
+fn serve_refuses_options_for_files() {
+    let output = call_toy_engine(&[
+        "--state-out",
+        "state.json",
+        "serve",
+        "--listen",
+        "127.0.0.1:0",
+    ]);
+
+    assert!(!output.status.success());
+    assert_eq!(
+        "Error: --state-out cannot be used with serve\n",
+        String::from_utf8_lossy(output.stderr.as_slice())
+    );
+}
+
+#[test]
+fn serve_refuses_output_format() {
+    let output = call_toy_engine(&[
+        "--output-format",
+        "json",
+        "serve",
+        "--listen",
+        "127.0.0.1:0",
+    ]);
+
+    assert!(!output.status.success());
+    assert_eq!(
+        "Error: --output-format cannot be used with serve\n",
+        String::from_utf8_lossy(output.stderr.as_slice())
+    );
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn serve_http_answers_with_json() {
+    use std::io::Read;
+
+    let (mut server, address) = start_server("serve-http");
+    let request = |request: String| {
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}"#;
+    let submitted = request(format!(
+        "POST /transactions HTTP/1.1\r\nHost: {address}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{deposit}",
+        deposit.len()
+    ));
+    let account = request(format!(
+        "GET /accounts/1 HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n"
+    ));
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert!(submitted.starts_with("HTTP/1.1 200 OK"));
+    assert!(submitted.ends_with(r#"{"status":"ok"}"#));
+    assert!(account.ends_with(
+        r#"{"client":1,"available":"2.5000","held":"0.0000","total":"2.5000","locked":false}"#
+    ));
 }